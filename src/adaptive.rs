@@ -0,0 +1,104 @@
+use crate::sensor::{Measurement, QUALITY_NO_SENSOR};
+use std::time::Duration;
+
+/// Sampling interval that follows how fast readings move: halved when
+/// consecutive readings differ by `fast_delta` or more, grown by half when
+/// they stay within `steady_delta`, and kept in between. Always in `min..=max`.
+pub struct Adaptive {
+    min: Duration,
+    max: Duration,
+    fast_delta: f32,
+    steady_delta: f32,
+    interval: Duration,
+    // Last temperature per sensor tag
+    last: Vec<(Option<String>, f32)>,
+}
+
+impl Adaptive {
+    /// Starts at `max`, so a steady sensor is never sampled faster than needed.
+    pub fn new(min: Duration, max: Duration, fast_delta: f32, steady_delta: f32) -> Self {
+        let max = max.max(min);
+        Self { min, max, fast_delta, steady_delta, interval: max, last: Vec::new() }
+    }
+
+    /// Adjusts the interval on the largest temperature change of any sensor
+    /// since its previous reading.
+    pub fn observe(&mut self, measurements: &[Measurement]) -> Duration {
+        let mut delta: Option<f32> = None;
+        for m in measurements.iter().filter(|m| m.quality & QUALITY_NO_SENSOR == 0) {
+            match self.last.iter_mut().find(|(tag, _)| *tag == m.tag) {
+                Some((_, last)) => {
+                    let d = (m.temperature - *last).abs();
+                    delta = Some(delta.map_or(d, |max| max.max(d)));
+                    *last = m.temperature;
+                }
+                None => self.last.push((m.tag.clone(), m.temperature)),
+            }
+        }
+        self.update(delta)
+    }
+
+    /// Takes the largest change since the previous sample; None when there was
+    /// nothing to compare, which keeps the interval.
+    pub fn update(&mut self, delta: Option<f32>) -> Duration {
+        if let Some(delta) = delta.map(f32::abs) {
+            if delta >= self.fast_delta {
+                self.interval /= 2;
+            } else if delta <= self.steady_delta {
+                self.interval += self.interval / 2;
+            }
+            self.interval = self.interval.clamp(self.min, self.max);
+        }
+        self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: Duration = Duration::from_secs(10);
+    const MAX: Duration = Duration::from_secs(300);
+
+    fn adaptive() -> Adaptive {
+        Adaptive::new(MIN, MAX, 1.0, 0.2)
+    }
+
+    #[test]
+    fn shrinks_on_change_and_clamps_at_min() {
+        let mut a = adaptive();
+        assert_eq!(a.update(Some(1.5)), Duration::from_secs(150));
+        assert_eq!(a.update(Some(-1.0)), Duration::from_secs(75));
+        for _ in 0..10 {
+            a.update(Some(3.0));
+        }
+        assert_eq!(a.update(Some(3.0)), MIN);
+    }
+
+    #[test]
+    fn grows_back_to_max_when_stable() {
+        let mut a = adaptive();
+        for _ in 0..10 {
+            a.update(Some(5.0));
+        }
+        assert_eq!(a.update(Some(0.1)), Duration::from_secs(15));
+        for _ in 0..20 {
+            a.update(Some(0.0));
+        }
+        assert_eq!(a.update(Some(0.2)), MAX);
+    }
+
+    #[test]
+    fn keeps_interval_in_between_or_without_delta() {
+        let mut a = adaptive();
+        a.update(Some(2.0));
+        assert_eq!(a.update(Some(0.5)), Duration::from_secs(150));
+        assert_eq!(a.update(None), Duration::from_secs(150));
+    }
+
+    #[test]
+    fn new_orders_min_and_max() {
+        let mut a = Adaptive::new(MAX, MIN, 1.0, 0.2);
+        assert_eq!(a.update(Some(2.0)), MAX);
+    }
+}
@@ -0,0 +1,63 @@
+/// Readings of one sensor folded together between two reports.
+pub struct Summary {
+    pub tag: Option<String>,
+    pub temperature: f32,
+    pub humidity: Option<f32>,
+    pub samples: u32,
+}
+
+struct Acc {
+    tag: Option<String>,
+    samples: u32,
+    temperature_sum: f32,
+    humidity_sum: f32,
+    humidity_samples: u32,
+}
+
+/// Averages readings per sensor tag until the next report takes them.
+#[derive(Default)]
+pub struct Aggregator {
+    accs: Vec<Acc>,
+}
+
+impl Aggregator {
+    pub fn is_empty(&self) -> bool {
+        self.accs.is_empty()
+    }
+
+    pub fn push(&mut self, tag: Option<&str>, temperature: f32, humidity: Option<f32>) {
+        let acc = match self.accs.iter().position(|a| a.tag.as_deref() == tag) {
+            Some(i) => &mut self.accs[i],
+            None => {
+                self.accs.push(Acc {
+                    tag: tag.map(str::to_owned),
+                    samples: 0,
+                    temperature_sum: 0.0,
+                    humidity_sum: 0.0,
+                    humidity_samples: 0,
+                });
+                self.accs.last_mut().unwrap()
+            }
+        };
+        acc.samples += 1;
+        acc.temperature_sum += temperature;
+        if let Some(h) = humidity {
+            acc.humidity_sum += h;
+            acc.humidity_samples += 1;
+        }
+    }
+
+    /// Mean of each sensor's readings since the last call, in first-seen order.
+    pub fn take(&mut self) -> Vec<Summary> {
+        self.accs
+            .drain(..)
+            .map(|a| Summary {
+                tag: a.tag,
+                temperature: a.temperature_sum / a.samples as f32,
+                humidity: (a.humidity_samples > 0)
+                    .then(|| a.humidity_sum / a.humidity_samples as f32),
+                samples: a.samples,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use esp_idf_hal::adc::attenuation::DB_11;
+use esp_idf_hal::adc::oneshot::config::{AdcChannelConfig, Calibration};
+use esp_idf_hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
+use esp_idf_hal::adc::ADC1;
+use esp_idf_hal::gpio::ADCPin;
+use std::sync::Arc;
+
+/// One analog read: the pin voltage and the value calibrated from it.
+#[derive(Clone, Copy, Debug)]
+pub struct AnalogReading {
+    pub millivolts: u16,
+    pub value: f32,
+}
+
+/// A voltage on an ADC1 pin, e.g. a soil-moisture or light sensor, mapped
+/// linearly as `millivolts * scale + offset`.
+pub struct Analog {
+    // Boxed so the pin type doesn't leak into the sampler
+    read_mv: Box<dyn FnMut() -> Result<u16> + Send>,
+    scale: f32,
+    offset: f32,
+}
+
+impl Analog {
+    pub fn new<P>(
+        adc: Arc<AdcDriver<'static, ADC1>>,
+        pin: P,
+        scale: f32,
+        offset: f32,
+    ) -> Result<Self>
+    where
+        P: ADCPin<Adc = ADC1> + Send + 'static,
+    {
+        let config = AdcChannelConfig {
+            attenuation: DB_11,
+            calibration: Calibration::Curve,
+            ..Default::default()
+        };
+        let mut channel = AdcChannelDriver::new(adc, pin, &config)?;
+        Ok(Self { read_mv: Box::new(move || Ok(channel.read()?)), scale, offset })
+    }
+
+    pub fn read(&mut self) -> Result<AnalogReading> {
+        let millivolts = (self.read_mv)()?;
+        Ok(AnalogReading { millivolts, value: millivolts as f32 * self.scale + self.offset })
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+/// Retry delays that double from `base` up to `cap` on each failure and fall
+/// back to `base` after a success.
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self { base, cap: cap.max(base), current: base }
+    }
+
+    pub fn base(&self) -> Duration {
+        self.base
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+
+    /// Delay before the next try after a failure. Up to a quarter of it is
+    /// added from `random` so devices that failed together don't retry together.
+    pub fn next_delay(&mut self, random: u32) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.cap);
+        let jitter_ms = delay.as_millis() as u64 / 4;
+        delay + Duration::from_millis(random as u64 % (jitter_ms + 1))
+    }
+}
+
+/// Random input for `next_delay` from the hardware RNG.
+pub fn random() -> u32 {
+    unsafe { esp_idf_sys::esp_random() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_secs(1);
+    const CAP: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(BASE, CAP);
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay(0).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn reset_returns_to_base() {
+        let mut backoff = Backoff::new(BASE, CAP);
+        for _ in 0..5 {
+            backoff.next_delay(0);
+        }
+        backoff.reset();
+        assert_eq!(backoff.next_delay(0), BASE);
+    }
+
+    #[test]
+    fn jitter_stays_within_a_quarter() {
+        for random in [1, 249, 250, 251, 999, u32::MAX] {
+            let mut backoff = Backoff::new(BASE, CAP);
+            let delay = backoff.next_delay(random);
+            assert!(delay >= BASE && delay <= BASE + BASE / 4, "{delay:?} for {random}");
+        }
+        // The largest draw lands exactly on the quarter
+        assert_eq!(Backoff::new(BASE, CAP).next_delay(250), BASE + BASE / 4);
+    }
+
+    #[test]
+    fn cap_below_base_is_raised() {
+        let mut backoff = Backoff::new(BASE, Duration::ZERO);
+        assert_eq!(backoff.next_delay(0), BASE);
+        assert_eq!(backoff.next_delay(0), BASE);
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What the producer does when the channel is full.
+#[derive(Clone, Copy, Debug)]
+pub enum Policy {
+    /// Evict the oldest queued item to make room (freshest data wins)
+    DropOldest,
+    /// Discard the item being sent (queued data wins)
+    DropNewest,
+    /// Wait up to the timeout for room, then discard the item
+    Block(Duration),
+}
+
+impl Policy {
+    pub fn parse(name: &str, block_timeout: Duration) -> Option<Self> {
+        match name {
+            "drop_oldest" => Some(Policy::DropOldest),
+            "drop_newest" => Some(Policy::DropNewest),
+            "block" => Some(Policy::Block(block_timeout)),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Policy::DropOldest => "drop_oldest",
+            Policy::DropNewest => "drop_newest",
+            Policy::Block(_) => "block",
+        }
+    }
+}
+
+// Both ends lock the receiver, but only briefly: the producer only when the
+// channel is full, and then the consumer's recv returns at once.
+type SharedReceiver<T> = Arc<Mutex<Receiver<T>>>;
+
+pub struct Producer<T> {
+    tx: SyncSender<T>,
+    // Weak, so dropping the consumer still disconnects the channel
+    rx: Weak<Mutex<Receiver<T>>>,
+    policy: Policy,
+    dropped: Arc<AtomicU32>,
+}
+
+pub struct Consumer<T> {
+    rx: SharedReceiver<T>,
+    dropped: Arc<AtomicU32>,
+}
+
+pub fn channel<T>(capacity: usize, policy: Policy) -> (Producer<T>, Consumer<T>) {
+    let (tx, rx) = sync_channel(capacity);
+    let rx = Arc::new(Mutex::new(rx));
+    let dropped = Arc::new(AtomicU32::new(0));
+    (
+        Producer { tx, rx: Arc::downgrade(&rx), policy, dropped: dropped.clone() },
+        Consumer { rx, dropped },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Sends `item` under the policy; returns false once the consumer is gone.
+    pub fn send(&self, item: T) -> bool {
+        let item = match self.tx.try_send(item) {
+            Ok(()) => return true,
+            Err(TrySendError::Disconnected(_)) => return false,
+            Err(TrySendError::Full(item)) => item,
+        };
+        match self.policy {
+            Policy::DropOldest => {
+                let mut item = item;
+                loop {
+                    let Some(rx) = self.rx.upgrade() else {
+                        return false;
+                    };
+                    // An Empty here means the consumer just made room
+                    if rx.lock().unwrap().try_recv().is_ok() {
+                        self.count_drop();
+                    }
+                    match self.tx.try_send(item) {
+                        Ok(()) => return true,
+                        Err(TrySendError::Disconnected(_)) => return false,
+                        Err(TrySendError::Full(back)) => item = back,
+                    }
+                }
+            }
+            Policy::DropNewest => {
+                self.count_drop();
+                true
+            }
+            Policy::Block(timeout) => {
+                let deadline = Instant::now() + timeout;
+                let mut item = item;
+                loop {
+                    thread::sleep(Duration::from_millis(10));
+                    match self.tx.try_send(item) {
+                        Ok(()) => return true,
+                        Err(TrySendError::Disconnected(_)) => return false,
+                        Err(TrySendError::Full(back)) => item = back,
+                    }
+                    if Instant::now() >= deadline {
+                        self.count_drop();
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn count_drop(&self) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!("Channel full ({}): {dropped} samples dropped", self.policy.as_str());
+    }
+}
+
+impl<T> Consumer<T> {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.rx.lock().unwrap().recv_timeout(timeout)
+    }
+
+    /// Samples the producer had to drop so far.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_oldest_keeps_the_newest() {
+        let (tx, rx) = channel(1, Policy::DropOldest);
+        assert!(tx.send(1));
+        assert!(tx.send(2));
+        assert_eq!(rx.recv_timeout(Duration::ZERO), Ok(2));
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_one() {
+        let (tx, rx) = channel(1, Policy::DropNewest);
+        assert!(tx.send(1));
+        assert!(tx.send(2));
+        assert_eq!(rx.recv_timeout(Duration::ZERO), Ok(1));
+        assert!(rx.recv_timeout(Duration::ZERO).is_err());
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[test]
+    fn block_times_out_and_counts_a_drop() {
+        let timeout = Duration::from_millis(50);
+        let (tx, rx) = channel(1, Policy::Block(timeout));
+        assert!(tx.send(1));
+        let started = Instant::now();
+        assert!(tx.send(2));
+        assert!(started.elapsed() >= timeout);
+        assert_eq!(rx.dropped(), 1);
+        assert_eq!(rx.recv_timeout(Duration::ZERO), Ok(1));
+    }
+
+    #[test]
+    fn gone_consumer_returns_false() {
+        for policy in [Policy::DropOldest, Policy::DropNewest, Policy::Block(Duration::ZERO)] {
+            let (tx, rx) = channel(1, policy);
+            assert!(tx.send(1));
+            drop(rx);
+            assert!(!tx.send(2), "{}", policy.as_str());
+        }
+    }
+}
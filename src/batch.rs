@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+/// Readings waiting to be posted together. Flushes after `max_count` items or
+/// once the oldest one is `max_age` old, whichever comes first.
+pub struct Batch<T> {
+    items: Vec<T>,
+    first_at: Option<Instant>,
+    max_count: usize,
+    max_age: Duration,
+}
+
+impl<T> Batch<T> {
+    pub fn new(max_count: usize, max_age: Duration) -> Self {
+        Self {
+            items: Vec::with_capacity(max_count),
+            first_at: None,
+            max_count: max_count.max(1),
+            max_age,
+        }
+    }
+
+    pub fn push(&mut self, item: T, now: Instant) {
+        if self.items.is_empty() {
+            self.first_at = Some(now);
+        }
+        self.items.push(item);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.first_at {
+            Some(first) => {
+                self.items.len() >= self.max_count || now.duration_since(first) >= self.max_age
+            }
+            None => false,
+        }
+    }
+
+    /// Time left before the age trigger fires, `None` while empty.
+    pub fn time_to_due(&self, now: Instant) -> Option<Duration> {
+        self.first_at
+            .map(|first| (first + self.max_age).saturating_duration_since(now))
+    }
+
+    pub fn take(&mut self) -> Vec<T> {
+        self.first_at = None;
+        std::mem::take(&mut self.items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flushes_on_count() {
+        let start = Instant::now();
+        let mut batch = Batch::new(3, Duration::from_secs(60));
+        assert!(!batch.is_due(start));
+        assert_eq!(batch.time_to_due(start), None);
+        batch.push(1, start);
+        batch.push(2, start + Duration::from_secs(1));
+        assert!(!batch.is_due(start + Duration::from_secs(2)));
+        batch.push(3, start + Duration::from_secs(2));
+        assert!(batch.is_due(start + Duration::from_secs(2)));
+        assert_eq!(batch.take(), [1, 2, 3]);
+        assert!(batch.is_empty());
+        assert!(!batch.is_due(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn flushes_on_age_of_oldest() {
+        let start = Instant::now();
+        let mut batch = Batch::new(10, Duration::from_secs(60));
+        let at = |secs| start + Duration::from_secs(secs);
+        batch.push("a", at(0));
+        batch.push("b", at(30));
+        assert_eq!(batch.time_to_due(at(30)), Some(Duration::from_secs(30)));
+        assert!(!batch.is_due(at(59)));
+        assert!(batch.is_due(at(60)));
+        assert_eq!(batch.time_to_due(at(90)), Some(Duration::ZERO));
+        batch.take();
+        // The age restarts with the next first item
+        batch.push("c", at(100));
+        assert_eq!(batch.time_to_due(at(100)), Some(Duration::from_secs(60)));
+        assert!(!batch.is_due(at(159)));
+    }
+}
@@ -0,0 +1,39 @@
+use crate::charge::voltage_to_pct;
+use anyhow::Result;
+use esp_idf_hal::adc::attenuation::DB_11;
+use esp_idf_hal::adc::oneshot::config::{AdcChannelConfig, Calibration};
+use esp_idf_hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
+use esp_idf_hal::adc::ADC1;
+use esp_idf_hal::gpio::Gpio3;
+use std::sync::Arc;
+
+/// Battery voltage on GPIO3 (ADC1) through a resistor divider.
+pub struct Battery {
+    channel: AdcChannelDriver<'static, Gpio3, Arc<AdcDriver<'static, ADC1>>>,
+    // Battery volts per volt seen at the pin
+    divider: f32,
+    curve: &'static [(f32, u8)],
+}
+
+impl Battery {
+    /// `adc` is shared, so an analog input can use ADC1 as well.
+    pub fn new(
+        adc: Arc<AdcDriver<'static, ADC1>>,
+        pin: Gpio3,
+        divider: f32,
+        curve: &'static [(f32, u8)],
+    ) -> Result<Self> {
+        let config = AdcChannelConfig {
+            attenuation: DB_11,
+            calibration: Calibration::Curve,
+            ..Default::default()
+        };
+        let channel = AdcChannelDriver::new(adc, pin, &config)?;
+        Ok(Self { channel, divider, curve })
+    }
+
+    pub fn read_pct(&mut self) -> Result<u8> {
+        let volts = self.channel.read()? as f32 / 1000.0 * self.divider;
+        Ok(voltage_to_pct(volts, self.curve))
+    }
+}
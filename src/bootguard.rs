@@ -0,0 +1,79 @@
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_WINDOW_START: &str = "cl_start";
+const KEY_BOOTS: &str = "cl_boots";
+const KEY_BOOT_COUNT: &str = "boot_count";
+
+pub struct CrashLoop {
+    pub boots: u32,
+    pub tripped: bool,
+}
+
+// The system clock survives soft resets and panics (but not power cycles),
+// which is exactly the span a crash loop lives in.
+fn clock_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Counts this boot and trips once more than `max_boots` land within
+/// `window_secs` of each other.
+pub fn record_boot(nvs: &mut EspNvs<NvsDefault>, max_boots: u32, window_secs: u64) -> CrashLoop {
+    let now = clock_secs();
+    let start = nvs.get_u64(KEY_WINDOW_START).ok().flatten();
+    let boots = nvs.get_u32(KEY_BOOTS).ok().flatten().unwrap_or(0);
+
+    let boots = match start {
+        Some(start) if now >= start && now - start <= window_secs => boots + 1,
+        _ => {
+            if let Err(e) = nvs.set_u64(KEY_WINDOW_START, now) {
+                log::warn!("Cannot store boot window: {:?}", e);
+            }
+            1
+        }
+    };
+    if let Err(e) = nvs.set_u32(KEY_BOOTS, boots) {
+        log::warn!("Cannot store boot count: {:?}", e);
+    }
+
+    CrashLoop { boots, tripped: boots > max_boots }
+}
+
+/// Forgets the recent boots once the firmware proved it can stay up.
+pub fn mark_stable(nvs: &mut EspNvs<NvsDefault>) {
+    if let Err(e) = nvs.remove(KEY_BOOTS).and_then(|_| nvs.remove(KEY_WINDOW_START)) {
+        log::warn!("Cannot clear boot count: {:?}", e);
+    }
+}
+
+/// Bumps the lifetime boot counter and returns the new value. NVS writes the
+/// new entry before erasing the old one, so a reset mid-write leaves the
+/// previous count rather than garbage.
+pub fn count_boot(nvs: &mut EspNvs<NvsDefault>) -> u32 {
+    let count = nvs.get_u32(KEY_BOOT_COUNT).ok().flatten().unwrap_or(0).wrapping_add(1);
+    if let Err(e) = nvs.set_u32(KEY_BOOT_COUNT, count) {
+        log::warn!("Cannot store lifetime boot count: {:?}", e);
+    }
+    count
+}
+
+pub fn reset_reason_str(reason: ResetReason) -> &'static str {
+    match reason {
+        ResetReason::Software => "software",
+        ResetReason::ExternalPin => "external_pin",
+        ResetReason::Watchdog => "watchdog",
+        ResetReason::Sdio => "sdio",
+        ResetReason::Panic => "panic",
+        ResetReason::InterruptWatchdog => "interrupt_watchdog",
+        ResetReason::PowerOn => "power_on",
+        ResetReason::Brownout => "brownout",
+        ResetReason::TaskWatchdog => "task_watchdog",
+        ResetReason::DeepSleep => "deep_sleep",
+        // USB and JTAG resets only exist on newer IDF versions
+        _ => "unknown",
+    }
+}
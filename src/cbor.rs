@@ -0,0 +1,75 @@
+use crate::error::AppError;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+/// A queued JSON document (one reading, or a batch array) re-encoded as CBOR
+/// (RFC 8949), field for field.
+pub fn from_json(json: &[u8]) -> Result<Vec<u8>, AppError> {
+    let value: Value = serde_json::from_slice(json).map_err(AppError::send)?;
+    let mut out = Vec::with_capacity(json.len() / 2);
+    ciborium::into_writer(&Compact(&value), &mut out).map_err(AppError::send)?;
+    Ok(out)
+}
+
+/// Writes a float as f32 when that prints the same as the JSON did, so a
+/// reading such as 23.4 takes 5 bytes instead of 9; ciborium narrows it to
+/// f16 on its own where that is exact.
+struct Compact<'a>(&'a Value);
+
+impl Serialize for Compact<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Number(n) if n.is_f64() => {
+                let wide = n.as_f64().unwrap_or_default();
+                let narrow = wide as f32;
+                if narrow.to_string().parse::<f64>() == Ok(wide) {
+                    s.serialize_f32(narrow)
+                } else {
+                    s.serialize_f64(wide)
+                }
+            }
+            Value::Array(items) => s.collect_seq(items.iter().map(Compact)),
+            Value::Object(map) => s.collect_map(map.iter().map(|(k, v)| (k, Compact(v)))),
+            other => other.serialize(s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(json: &str) -> Vec<u8> {
+        from_json(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn narrows_floats_that_fit() {
+        // f32 (0xfa), as 23.4 is not exact in f16
+        assert_eq!(encode("23.4"), [0xfa, 0x41, 0xbb, 0x33, 0x33]);
+        // Prints differently as f32, so it stays f64 (0xfb)
+        let wide = encode("23.456789012345");
+        assert_eq!(wide[0], 0xfb);
+        assert_eq!(f64::from_be_bytes(wide[1..].try_into().unwrap()), 23.456789012345);
+    }
+
+    #[test]
+    fn integers_pass_through() {
+        assert_eq!(encode("21"), [0x15]);
+        assert_eq!(encode("-3"), [0x22]);
+        assert_eq!(encode("1700000000"), [0x1a, 0x65, 0x53, 0xf1, 0x00]);
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let batch = r#"[{"temperature":23.4,"samples":3,"id":"mk2-1"},{"temperature":-1.5}]"#;
+        let back: Value = ciborium::from_reader(encode(batch).as_slice()).unwrap();
+        let items = back.as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["temperature"].as_f64().unwrap() as f32, 23.4);
+        assert_eq!(items[0]["samples"], 3);
+        assert_eq!(items[0]["id"], "mk2-1");
+        // Exact in f16, which ciborium picks on its own
+        assert_eq!(items[1]["temperature"], -1.5);
+    }
+}
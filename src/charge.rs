@@ -0,0 +1,67 @@
+/// Single-cell LiPo/Li-ion under light load, (volts, percent) by rising voltage.
+pub const LIPO_CURVE: &[(f32, u8)] = &[
+    (3.30, 0),
+    (3.50, 5),
+    (3.60, 10),
+    (3.70, 25),
+    (3.75, 40),
+    (3.80, 55),
+    (3.85, 65),
+    (3.95, 80),
+    (4.05, 90),
+    (4.20, 100),
+];
+
+/// Interpolates `v` linearly between the curve points, clamped to its ends.
+pub fn voltage_to_pct(v: f32, curve: &[(f32, u8)]) -> u8 {
+    let (Some(&(v_min, p_min)), Some(&(v_max, p_max))) = (curve.first(), curve.last()) else {
+        return 0;
+    };
+    if v <= v_min {
+        return p_min;
+    }
+    if v >= v_max {
+        return p_max;
+    }
+    curve
+        .windows(2)
+        .find(|w| v <= w[1].0)
+        .map_or(p_max, |w| {
+            let ((v0, p0), (v1, p1)) = (w[0], w[1]);
+            let t = (v - v0) / (v1 - v0);
+            (p0 as f32 + t * (p1 as f32 - p0 as f32)).round() as u8
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pct(v: f32) -> u8 {
+        voltage_to_pct(v, LIPO_CURVE)
+    }
+
+    #[test]
+    fn exact_at_the_knots() {
+        for &(v, p) in LIPO_CURVE {
+            assert_eq!(pct(v), p, "{v} V");
+        }
+    }
+
+    #[test]
+    fn interpolates_between_knots() {
+        assert_eq!(pct(3.56), 8);
+        assert_eq!(pct(3.72), 31);
+        assert_eq!(pct(4.00), 85);
+        assert_eq!(pct(4.125), 95);
+    }
+
+    #[test]
+    fn clamps_beyond_the_ends() {
+        assert_eq!(pct(0.0), 0);
+        assert_eq!(pct(3.0), 0);
+        assert_eq!(pct(4.35), 100);
+        assert_eq!(voltage_to_pct(3.7, &[]), 0);
+        assert_eq!(voltage_to_pct(3.7, &[(3.5, 20)]), 20);
+    }
+}
@@ -0,0 +1,207 @@
+use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+use esp_idf_sys as sys;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Anything before this means the wall clock was never set
+const MIN_PLAUSIBLE_UNIX: u64 = 1_704_067_200; // 2024-01-01
+
+/// Where the wall-clock time comes from, best last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimeSource {
+    None,
+    Rtc,
+    Http,
+    Ntp,
+}
+
+impl TimeSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimeSource::None => "none",
+            TimeSource::Rtc => "rtc",
+            TimeSource::Http => "http",
+            TimeSource::Ntp => "ntp",
+        }
+    }
+}
+
+/// Time of a reading, taken at capture so buffered readings keep it.
+#[derive(Clone, Copy, Debug)]
+pub struct Stamp {
+    pub unix_ms: Option<u64>,
+    pub uptime_ms: u64,
+    pub source: TimeSource,
+}
+
+pub struct Clock {
+    sntp: Option<EspSntp<'static>>,
+    source: TimeSource,
+    boot: Instant,
+}
+
+fn wall_clock_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Clock {
+    pub fn new(boot: Instant) -> Self {
+        // The RTC keeps counting across soft resets and deep sleep
+        let source = if wall_clock_secs() >= MIN_PLAUSIBLE_UNIX {
+            TimeSource::Rtc
+        } else {
+            TimeSource::None
+        };
+        let sntp = EspSntp::new_default()
+            .map_err(|e| log::warn!("SNTP unavailable: {:?}", e))
+            .ok();
+        Self { sntp, source, boot }
+    }
+
+    /// Blocks until SNTP completes or `timeout` runs out; true when synced.
+    pub fn wait_for_ntp(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        while self.refresh_source() != TimeSource::Ntp {
+            if self.sntp.is_none() || started.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+
+    fn refresh_source(&self) -> TimeSource {
+        match &self.sntp {
+            Some(sntp) if sntp.get_sync_status() == SyncStatus::Completed => TimeSource::Ntp,
+            _ => self.source,
+        }
+    }
+
+    /// Sets the clock from a response `Date` header unless NTP already did.
+    pub fn observe_http_date(&mut self, date: &str) {
+        if self.refresh_source() >= TimeSource::Http {
+            return;
+        }
+        let Some(secs) = parse_http_date(date) else {
+            log::debug!("Unparsable Date header '{date}'");
+            return;
+        };
+        let tv = sys::timeval { tv_sec: secs as _, tv_usec: 0 };
+        if unsafe { sys::settimeofday(&tv, core::ptr::null()) } == 0 {
+            log::info!("🕒 Clock set from HTTP Date: {date}");
+            self.source = TimeSource::Http;
+        }
+    }
+
+    pub fn now(&self) -> Stamp {
+        let source = self.refresh_source();
+        let unix_ms = (source != TimeSource::None).then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0)
+        });
+        Stamp {
+            unix_ms,
+            uptime_ms: self.boot.elapsed().as_millis() as u64,
+            source,
+        }
+    }
+
+    /// Unix seconds, but only from a source the server can agree with (NTP or
+    /// its own `Date`); a clock merely kept across a reset doesn't count.
+    pub fn synced_unix_secs(&self) -> Option<u64> {
+        let stamp = self.now();
+        if stamp.source < TimeSource::Http {
+            return None;
+        }
+        stamp.unix_ms.map(|ms| ms / 1000)
+    }
+}
+
+/// Parses an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_whitespace();
+    let _weekday = parts.next()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut hms = parts.next()?.split(':').map(|v| v.parse::<u64>().ok());
+    let (h, m, s) = (hms.next()??, hms.next()??, hms.next()??);
+    if parts.next()? != "GMT" || !(1..=31).contains(&day) || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+
+    // Days since 1970-01-01 (Howard Hinnant's days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * 86_400 + h * 3600 + m * 60 + s)
+}
+
+/// Year, month and day of a day count since 1970-01-01 (Howard Hinnant's
+/// civil_from_days, the inverse of the above).
+#[cfg_attr(not(feature = "sdcard"), allow(dead_code))]
+pub fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_rfc_7231_example() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+    }
+
+    #[test]
+    fn parses_a_leap_day() {
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"), Some(1_709_164_800));
+        assert_eq!(parse_http_date("Fri, 01 Mar 2024 00:00:00 GMT"), Some(1_709_251_200));
+        assert_eq!(civil_date(1_709_164_800 / 86_400), (2024, 2, 29));
+    }
+
+    #[test]
+    fn rejects_other_zones_and_bad_fields() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 +0000"), None);
+        assert_eq!(parse_http_date("Sun, 06 Now 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+    }
+}
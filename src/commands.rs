@@ -0,0 +1,99 @@
+use crate::backoff::{self, Backoff};
+use crate::transport::http_config;
+use anyhow::{anyhow, Context, Result};
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::client::{Configuration as HttpCfg, EspHttpConnection};
+use serde::Deserialize;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+// Asked of the server; the client gives up a bit later
+const HOLD_SECS: u64 = 25;
+const MAX_BODY: usize = 2048;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+const POLLER_STACK: usize = 8 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Relay { on: bool },
+    Reboot,
+    PostNow,
+    Config { key: String, value: String },
+}
+
+// The server may answer with one command or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Reply {
+    One(Command),
+    Many(Vec<Command>),
+}
+
+/// Holds one GET open until the server has commands or its hold time runs
+/// out. An empty list means it timed out.
+fn poll_once(url: &str) -> Result<Vec<Command>> {
+    let conn = EspHttpConnection::new(&HttpCfg {
+        timeout: Some(Duration::from_secs(HOLD_SECS + 10)),
+        ..http_config(url)
+    })?;
+    let mut client = Client::wrap(conn);
+    let sep = if url.contains('?') { '&' } else { '?' };
+    let mut resp = client.get(&format!("{url}{sep}timeout={HOLD_SECS}"))?.submit()?;
+    match resp.status() {
+        204 => return Ok(Vec::new()),
+        200 => {}
+        status => return Err(anyhow!("Commands HTTP {status}")),
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = resp.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+        if body.len() > MAX_BODY {
+            return Err(anyhow!("Commands body too large"));
+        }
+    }
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Vec::new());
+    }
+    Ok(match serde_json::from_slice(&body)? {
+        Reply::One(command) => vec![command],
+        Reply::Many(commands) => commands,
+    })
+}
+
+/// Long-polls `url` on its own thread and forwards every command to `tx`.
+pub fn spawn_long_poll(url: &'static str, tx: Sender<Command>) -> Result<()> {
+    thread::Builder::new()
+        .name("commands".into())
+        .stack_size(POLLER_STACK)
+        .spawn(move || {
+            let mut backoff = Backoff::new(BACKOFF_BASE, BACKOFF_MAX);
+            loop {
+                match poll_once(url) {
+                    Ok(commands) => {
+                        backoff.reset();
+                        for command in commands {
+                            if tx.send(command).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let delay = backoff.next_delay(backoff::random());
+                        log::warn!("Command poll failed, retrying in {:?}: {:?}", delay, e);
+                        thread::sleep(delay);
+                    }
+                }
+            }
+        })
+        .context("Command thread")?;
+    Ok(())
+}
@@ -0,0 +1,829 @@
+use crate::dht::SensorKind;
+use crate::persist;
+use crate::schedule::Schedule;
+use crate::telemetry::{Encoding, TempUnit, Template};
+use embedded_svc::http::Method;
+use log::LevelFilter;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+// Interval bounds accepted from storage
+const MIN_INTERVAL_SECS: u64 = 2;
+const MAX_INTERVAL_SECS: u64 = 3600;
+// Each one can cost a full HTTP_TIMEOUT when everything is down
+const MAX_FALLBACK_URLS: usize = 3;
+// Dead-man timeout bounds, in minutes
+const MIN_DEAD_MAN_MINS: u64 = 5;
+const MAX_DEAD_MAN_MINS: u64 = 24 * 60;
+// Setup AP channel and client bounds; the country may still move the channel
+const MAX_AP_CHANNEL: u8 = 14;
+const MAX_AP_CLIENTS: u16 = 10;
+// DHCP wait bounds for a join, in seconds
+const MIN_DHCP_SECS: u64 = 5;
+const MAX_DHCP_SECS: u64 = 120;
+
+const KEY_URL: &str = "cfg_url";
+const KEY_INTERVAL: &str = "cfg_interval";
+const KEY_REPORT: &str = "cfg_report";
+const KEY_SCHEDULE: &str = "cfg_schedule";
+const KEY_COUNTRY: &str = "cfg_country";
+const KEY_SENSOR: &str = "cfg_sensor";
+const KEY_DEVICE_ID: &str = "cfg_device_id";
+const KEY_TOKEN: &str = "cfg_token";
+const KEY_UNIT: &str = "cfg_unit";
+const KEY_STATIC_IP: &str = "cfg_static_ip";
+const KEY_HEARTBEAT: &str = "cfg_heartbeat";
+const KEY_HTTP_METHOD: &str = "cfg_http_method";
+const KEY_HTTP_HEADERS: &str = "cfg_headers";
+const KEY_ANALOG_SCALE: &str = "cfg_an_scale";
+const KEY_ANALOG_OFFSET: &str = "cfg_an_offset";
+const KEY_TEMPLATE: &str = "cfg_template";
+const KEY_DEAD_MAN: &str = "cfg_dead_man";
+const KEY_LOG_LEVEL: &str = "cfg_log_level";
+const KEY_QUIET: &str = "cfg_quiet";
+const KEY_SD_LOG: &str = "cfg_sd_log";
+const KEY_WIFI_REBUILD: &str = "cfg_wifi_rbld";
+const KEY_FALLBACK_URLS: &str = "cfg_fallbacks";
+const KEY_AP_NET: &str = "cfg_ap_net";
+const KEY_ENCODING: &str = "cfg_encoding";
+const KEY_AP_SSID: &str = "cfg_ap_ssid";
+const KEY_AP_CHANNEL: &str = "cfg_ap_channel";
+const KEY_AP_CLIENTS: &str = "cfg_ap_clients";
+const KEY_DHCP_TIMEOUT: &str = "cfg_dhcp_secs";
+
+/// Shown for the token by `Config::settings`; storing it back is a no-op for
+/// callers to skip, so an exported config can be imported unchanged.
+pub const REDACTED: &str = "<redacted>";
+
+// Set by the transport itself; an extra header must not override them
+const RESERVED_HEADERS: &[&str] =
+    &["content-type", "content-length", "content-encoding", "authorization", "host"];
+
+/// Settings stored in NVS, each falling back to its compiled-in default.
+pub struct Config {
+    pub url: String,
+    /// Tried in order when `url` gives no 2xx; http(s) only
+    pub fallback_urls: Vec<String>,
+    pub sample_interval: Duration,
+    /// None reports every sample as it comes
+    pub report_interval: Option<Duration>,
+    /// None sends no heartbeat between measurements
+    pub heartbeat_interval: Option<Duration>,
+    /// None never reopens setup over failing posts
+    pub dead_man: Option<Duration>,
+    pub schedule: String,
+    pub wifi_country: String,
+    pub sensor: SensorKind,
+    /// None names the device after its MAC
+    pub device_id: Option<String>,
+    /// Sent as `Authorization: Bearer`; None sends no auth header
+    pub auth_token: Option<String>,
+    /// Unit the temperature is sent in
+    pub unit: TempUnit,
+    /// None takes the station address from DHCP
+    pub static_ip: Option<StaticIp>,
+    /// Name of the setup AP
+    pub ap_ssid: String,
+    /// Preferred setup AP channel; the country's first allowed one replaces it
+    pub ap_channel: u8,
+    /// Clients the setup AP takes at once
+    pub ap_max_clients: u16,
+    /// Address and DHCP pool of the setup AP
+    pub ap_net: ApNet,
+    /// How long a join waits for a DHCP lease once associated
+    pub dhcp_timeout: Duration,
+    /// Method telemetry is sent with over HTTP
+    pub http_method: Method,
+    /// Sent with every HTTP post, after the built-in headers
+    pub http_headers: Vec<(String, String)>,
+    /// Body encoding of the HTTP sink; CBOR needs the cbor feature
+    pub encoding: Encoding,
+    /// Analog input calibration: value = millivolts * scale + offset
+    pub analog_scale: f32,
+    pub analog_offset: f32,
+    /// Payload shape with `{{field}}` placeholders; None sends the usual JSON
+    pub payload_template: Option<String>,
+    pub log_level: LevelFilter,
+    /// Holds back the per-cycle success lines, keeping warnings and errors
+    pub quiet: bool,
+    /// Also appends every payload to the SD card; needs the sdcard feature
+    pub sd_log: bool,
+    /// Failed flushes in a row, Wi-Fi claiming to be up, before the driver is
+    /// restarted; None never does
+    pub wifi_rebuild: Option<u32>,
+    /// Stored fields that were invalid and replaced by their default
+    pub warnings: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            url: crate::DEFAULT_URL.to_owned(),
+            fallback_urls: crate::FALLBACK_URLS.iter().map(|&url| url.to_owned()).collect(),
+            sample_interval: crate::SAMPLE_INTERVAL,
+            report_interval: crate::REPORT_INTERVAL,
+            heartbeat_interval: crate::HEARTBEAT_INTERVAL,
+            dead_man: crate::DEAD_MAN_TIMEOUT,
+            schedule: crate::SCHEDULE.to_owned(),
+            wifi_country: crate::WIFI_COUNTRY.to_owned(),
+            sensor: crate::SENSOR_KIND,
+            device_id: crate::DEVICE_ID.map(str::to_owned),
+            auth_token: crate::AUTH_TOKEN.map(str::to_owned),
+            unit: crate::TEMP_UNIT,
+            static_ip: None,
+            ap_ssid: crate::SETUP_SSID.to_owned(),
+            ap_channel: crate::AP_CHANNEL,
+            ap_max_clients: crate::SETUP_MAX_CLIENTS,
+            ap_net: crate::SETUP_AP_NET,
+            dhcp_timeout: crate::JOIN_DHCP_TIMEOUT,
+            http_method: crate::HTTP_METHOD,
+            http_headers: crate::HTTP_HEADERS
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            encoding: crate::ENCODING,
+            analog_scale: crate::ANALOG_SCALE,
+            analog_offset: crate::ANALOG_OFFSET,
+            payload_template: crate::PAYLOAD_TEMPLATE.map(str::to_owned),
+            log_level: crate::LOG_LEVEL,
+            quiet: crate::LOG_QUIET,
+            sd_log: crate::SD_LOG,
+            wifi_rebuild: crate::WIFI_REBUILD_AFTER,
+            warnings: 0,
+        }
+    }
+}
+
+/// An http(s) endpoint, or an mqtt(s) broker for the MQTT transport.
+pub fn valid_url(url: &str) -> bool {
+    ["http://", "https://", "mqtt://", "mqtts://"].iter().any(|scheme| url.starts_with(scheme))
+}
+
+/// Up to 32 letters, digits, `-` or `_`, so it also fits in an MQTT topic.
+pub fn valid_device_id(id: &str) -> bool {
+    (1..=32).contains(&id.len())
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Up to 128 printable ASCII characters without spaces, so it fits in a header.
+pub fn valid_token(token: &str) -> bool {
+    (1..=128).contains(&token.len()) && token.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// 802.11 caps an SSID at 32 bytes; a NUL would cut it short in the C driver.
+pub fn validate_ssid(ssid: &str) -> Result<(), String> {
+    if ssid.is_empty() {
+        return Err("SSID is empty".into());
+    }
+    if ssid.len() > 32 {
+        return Err(format!("SSID is {} bytes, the limit is 32", ssid.len()));
+    }
+    if ssid.contains('\0') {
+        return Err("SSID contains a NUL character".into());
+    }
+    Ok(())
+}
+
+/// A header name made of letters, digits and `-` that the transport doesn't
+/// set itself.
+pub fn valid_header_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !RESERVED_HEADERS.contains(&lower.as_str())
+        && !lower.starts_with("x-mk2-")
+}
+
+pub fn valid_country(cc: &str) -> bool {
+    // "01" is the IDF worldwide-safe domain
+    cc == "01" || (cc.len() == 2 && cc.bytes().all(|b| b.is_ascii_uppercase()))
+}
+
+/// A fixed station address, stored as "ip,gateway,netmask".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    /// Netmask as a prefix length, e.g. 24 for 255.255.255.0
+    pub prefix: u8,
+}
+
+impl StaticIp {
+    pub fn parse(v: &str) -> Result<Self, String> {
+        let mut parts = v.split(',').map(str::trim);
+        let mut addr = |what: &str| {
+            let part = parts.next().unwrap_or_default();
+            part.parse::<Ipv4Addr>().map_err(|_| format!("bad {what} '{part}'"))
+        };
+        let (ip, gateway, netmask) = (addr("address")?, addr("gateway")?, addr("netmask")?);
+        if parts.next().is_some() {
+            return Err(format!("'{v}' is not ip,gateway,netmask"));
+        }
+        let prefix = parse_prefix(netmask)?;
+        let mask = u32::MAX << (32 - prefix as u32);
+        let host = !mask;
+        let net = u32::from(ip) & mask;
+        let subnet = Ipv4Addr::from(net);
+        for (what, addr) in [("address", ip), ("gateway", gateway)] {
+            let bits = u32::from(addr);
+            if bits & mask != net || bits & host == 0 || bits & host == host {
+                return Err(format!("{what} {addr} is not a host in {subnet}/{prefix}"));
+            }
+        }
+        if ip == gateway {
+            return Err("address and gateway are the same".into());
+        }
+        Ok(Self { ip, gateway, prefix })
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX << (32 - self.prefix as u32))
+    }
+}
+
+impl fmt::Display for StaticIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.ip, self.gateway, self.netmask())
+    }
+}
+
+/// The setup AP's own address and the range its DHCP server hands out,
+/// stored as "ip,netmask,pool start,pool end".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApNet {
+    /// Gateway and DNS server for the clients, and where the page is served
+    pub ip: Ipv4Addr,
+    pub prefix: u8,
+    pub dhcp_start: Ipv4Addr,
+    pub dhcp_end: Ipv4Addr,
+}
+
+impl ApNet {
+    pub fn parse(v: &str) -> Result<Self, String> {
+        let mut parts = v.split(',').map(str::trim);
+        let mut addr = |what: &str| {
+            let part = parts.next().unwrap_or_default();
+            part.parse::<Ipv4Addr>().map_err(|_| format!("bad {what} '{part}'"))
+        };
+        let (ip, netmask) = (addr("address")?, addr("netmask")?);
+        let (dhcp_start, dhcp_end) = (addr("pool start")?, addr("pool end")?);
+        if parts.next().is_some() {
+            return Err(format!("'{v}' is not ip,netmask,pool start,pool end"));
+        }
+        let net = Self { ip, prefix: parse_prefix(netmask)?, dhcp_start, dhcp_end };
+        net.check()?;
+        Ok(net)
+    }
+
+    /// A private subnet with the AP and the whole pool in it, the AP's own
+    /// address outside the pool. Also run on the compiled-in default.
+    pub fn check(&self) -> Result<(), String> {
+        if !self.ip.is_private() {
+            return Err(format!("address {} is not in a private range", self.ip));
+        }
+        let mask = u32::MAX << (32 - self.prefix as u32);
+        let host = !mask;
+        let net = u32::from(self.ip) & mask;
+        let subnet = Ipv4Addr::from(net);
+        for (what, addr) in
+            [("address", self.ip), ("pool start", self.dhcp_start), ("pool end", self.dhcp_end)]
+        {
+            let bits = u32::from(addr);
+            if bits & mask != net || bits & host == 0 || bits & host == host {
+                return Err(format!("{what} {addr} is not a host in {subnet}/{}", self.prefix));
+            }
+        }
+        let pool = u32::from(self.dhcp_start)..=u32::from(self.dhcp_end);
+        if pool.is_empty() {
+            return Err("pool starts after it ends".into());
+        }
+        if pool.contains(&u32::from(self.ip)) {
+            return Err(format!("pool includes the address {}", self.ip));
+        }
+        Ok(())
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX << (32 - self.prefix as u32))
+    }
+}
+
+impl fmt::Display for ApNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.ip, self.netmask(), self.dhcp_start, self.dhcp_end)
+    }
+}
+
+/// Prefix length of a netmask with contiguous ones and room for two hosts.
+fn parse_prefix(netmask: Ipv4Addr) -> Result<u8, String> {
+    let mask = u32::from(netmask);
+    let prefix = mask.leading_ones();
+    if mask.checked_shl(prefix).unwrap_or(0) != 0 || !(1..=30).contains(&prefix) {
+        return Err(format!("bad netmask {netmask}"));
+    }
+    Ok(prefix as u8)
+}
+
+struct Loader<'a> {
+    nvs: &'a EspNvs<NvsDefault>,
+    warnings: u32,
+}
+
+impl Loader<'_> {
+    /// The stored value of `key` if it parses, else `default`; absent keys are
+    /// not a warning.
+    fn field<T>(
+        &mut self,
+        key: &str,
+        default: T,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> T {
+        let mut buf = [0u8; 256];
+        let err = match self.nvs.get_str(key, &mut buf) {
+            Ok(None) => return default,
+            Ok(Some(raw)) => match parse(raw) {
+                Ok(value) => return value,
+                Err(e) => e,
+            },
+            Err(e) => format!("unreadable ({:?})", e),
+        };
+        log::warn!("Config '{key}': {err}, using the default");
+        self.warnings += 1;
+        default
+    }
+}
+
+fn parse_url(v: &str) -> Result<String, String> {
+    valid_url(v)
+        .then(|| v.to_owned())
+        .ok_or_else(|| format!("'{v}' is not an http(s) or mqtt(s) URL"))
+}
+
+// Comma-separated, empty for none
+fn parse_fallback_urls(v: &str) -> Result<Vec<String>, String> {
+    let urls: Vec<String> =
+        v.split(',').map(str::trim).filter(|url| !url.is_empty()).map(str::to_owned).collect();
+    if urls.len() > MAX_FALLBACK_URLS {
+        return Err(format!("more than {MAX_FALLBACK_URLS} fallback URLs"));
+    }
+    match urls.iter().find(|url| !url.starts_with("http://") && !url.starts_with("https://")) {
+        Some(url) => Err(format!("fallback '{url}' is not an http(s) URL")),
+        None => Ok(urls),
+    }
+}
+
+fn parse_ap_ssid(v: &str) -> Result<String, String> {
+    validate_ssid(v).map(|_| v.to_owned())
+}
+
+fn parse_ap_channel(v: &str) -> Result<u8, String> {
+    v.parse::<u8>()
+        .ok()
+        .filter(|ch| (1..=MAX_AP_CHANNEL).contains(ch))
+        .ok_or_else(|| format!("AP channel '{v}' not in 1-{MAX_AP_CHANNEL}"))
+}
+
+fn parse_ap_clients(v: &str) -> Result<u16, String> {
+    v.parse::<u16>()
+        .ok()
+        .filter(|n| (1..=MAX_AP_CLIENTS).contains(n))
+        .ok_or_else(|| format!("AP client limit '{v}' not in 1-{MAX_AP_CLIENTS}"))
+}
+
+fn parse_dhcp_timeout(v: &str) -> Result<Duration, String> {
+    v.parse::<u64>()
+        .ok()
+        .filter(|s| (MIN_DHCP_SECS..=MAX_DHCP_SECS).contains(s))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("DHCP timeout '{v}' not in {MIN_DHCP_SECS}-{MAX_DHCP_SECS}s"))
+}
+
+fn parse_interval(v: &str) -> Result<Duration, String> {
+    v.parse::<u64>()
+        .ok()
+        .filter(|s| (MIN_INTERVAL_SECS..=MAX_INTERVAL_SECS).contains(s))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("interval '{v}' not in {MIN_INTERVAL_SECS}-{MAX_INTERVAL_SECS}s"))
+}
+
+// "0" turns aggregation (or the heartbeat) off
+fn parse_report_interval(v: &str) -> Result<Option<Duration>, String> {
+    if v == "0" {
+        return Ok(None);
+    }
+    parse_interval(v).map(Some)
+}
+
+// In minutes, "0" for off
+fn parse_dead_man(v: &str) -> Result<Option<Duration>, String> {
+    match v.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(mins) if (MIN_DEAD_MAN_MINS..=MAX_DEAD_MAN_MINS).contains(&mins) => {
+            Ok(Some(Duration::from_secs(mins * 60)))
+        }
+        _ => Err(format!(
+            "dead-man timeout '{v}' not 0 or {MIN_DEAD_MAN_MINS}-{MAX_DEAD_MAN_MINS} minutes"
+        )),
+    }
+}
+
+// Failed flushes, "0" for off; only below MAX_POST_FAILURES does it come before the reboot
+fn parse_wifi_rebuild(v: &str) -> Result<Option<u32>, String> {
+    match v.parse::<u32>() {
+        Ok(0) => Ok(None),
+        Ok(n) if n < crate::MAX_POST_FAILURES => Ok(Some(n)),
+        _ => Err(format!("Wi-Fi rebuild '{v}' not 0 or 1-{}", crate::MAX_POST_FAILURES - 1)),
+    }
+}
+
+fn parse_schedule(v: &str) -> Result<String, String> {
+    Schedule::parse(v).map(|_| v.to_owned())
+}
+
+fn parse_country(v: &str) -> Result<String, String> {
+    valid_country(v)
+        .then(|| v.to_owned())
+        .ok_or_else(|| format!("bad country code '{v}'"))
+}
+
+fn parse_device_id(v: &str) -> Result<Option<String>, String> {
+    valid_device_id(v)
+        .then(|| Some(v.to_owned()))
+        .ok_or_else(|| format!("bad device id '{v}'"))
+}
+
+// Never echoes the value, which is a secret
+fn parse_token(v: &str) -> Result<Option<String>, String> {
+    valid_token(v)
+        .then(|| Some(v.to_owned()))
+        .ok_or_else(|| "auth token must be 1-128 printable characters".to_owned())
+}
+
+fn parse_unit(v: &str) -> Result<TempUnit, String> {
+    TempUnit::parse(v).ok_or_else(|| format!("unknown unit '{v}', expected C or F"))
+}
+
+fn parse_static_ip(v: &str) -> Result<Option<StaticIp>, String> {
+    StaticIp::parse(v).map(Some)
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Put => "PUT",
+        Method::Patch => "PATCH",
+        // Only the three parse_http_method accepts are ever stored
+        _ => "POST",
+    }
+}
+
+fn parse_http_method(v: &str) -> Result<Method, String> {
+    match v {
+        "POST" => Ok(Method::Post),
+        "PUT" => Ok(Method::Put),
+        "PATCH" => Ok(Method::Patch),
+        _ => Err(format!("unsupported method '{v}', expected POST, PUT or PATCH")),
+    }
+}
+
+// "Name: value" pairs split by ';', e.g. "X-Api-Version: 2; X-Device-Group: attic"
+fn parse_http_headers(v: &str) -> Result<Vec<(String, String)>, String> {
+    v.split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) =
+                pair.split_once(':').ok_or_else(|| format!("header '{pair}' has no ':'"))?;
+            let (name, value) = (name.trim(), value.trim());
+            if !valid_header_name(name) {
+                return Err(format!("bad or reserved header name '{name}'"));
+            }
+            if !value.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+                return Err(format!("header '{name}' has a non-printable value"));
+            }
+            Ok((name.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+fn parse_factor(v: &str) -> Result<f32, String> {
+    v.parse::<f32>()
+        .ok()
+        .filter(|f| f.is_finite())
+        .ok_or_else(|| format!("'{v}' is not a number"))
+}
+
+fn parse_template(v: &str) -> Result<Option<String>, String> {
+    // Leaves room for the terminating NUL in the loader's buffer
+    if v.len() > 255 {
+        return Err("template longer than 255 bytes".into());
+    }
+    Template::parse(v).map(|_| Some(v.to_owned()))
+}
+
+fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off | LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug | LevelFilter::Trace => "debug",
+    }
+}
+
+fn parse_log_level(v: &str) -> Result<LevelFilter, String> {
+    match v {
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        _ => Err(format!("unknown log level '{v}', expected error, warn, info or debug")),
+    }
+}
+
+fn parse_on_off(v: &str) -> Result<bool, String> {
+    match v {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("'{v}' is not on or off")),
+    }
+}
+
+fn parse_sd_log(v: &str) -> Result<bool, String> {
+    let on = parse_on_off(v)?;
+    if on && !cfg!(feature = "sdcard") {
+        return Err("SD logging needs a build with the sdcard feature".into());
+    }
+    Ok(on)
+}
+
+fn parse_encoding(v: &str) -> Result<Encoding, String> {
+    match Encoding::parse(v) {
+        Some(Encoding::Cbor) if !cfg!(feature = "cbor") => {
+            Err("CBOR bodies need a build with the cbor feature".into())
+        }
+        Some(encoding) => Ok(encoding),
+        None => Err(format!("unknown encoding '{v}', expected json or cbor")),
+    }
+}
+
+fn parse_sensor(v: &str) -> Result<SensorKind, String> {
+    SensorKind::parse(v).ok_or_else(|| format!("unknown sensor '{v}', expected dht11 or dht22"))
+}
+
+impl Config {
+    /// Validates every stored field on its own, so one bad value never costs
+    /// the others.
+    pub fn load(nvs: &EspNvs<NvsDefault>) -> Self {
+        let default = Self::default();
+        let mut loader = Loader { nvs, warnings: 0 };
+
+        let url = loader.field(KEY_URL, default.url, parse_url);
+        let fallback_urls =
+            loader.field(KEY_FALLBACK_URLS, default.fallback_urls, parse_fallback_urls);
+        let sample_interval = loader.field(KEY_INTERVAL, default.sample_interval, parse_interval);
+        let report_interval =
+            loader.field(KEY_REPORT, default.report_interval, parse_report_interval);
+        let heartbeat_interval =
+            loader.field(KEY_HEARTBEAT, default.heartbeat_interval, parse_report_interval);
+        let dead_man = loader.field(KEY_DEAD_MAN, default.dead_man, parse_dead_man);
+        let schedule = loader.field(KEY_SCHEDULE, default.schedule, parse_schedule);
+        let wifi_country = loader.field(KEY_COUNTRY, default.wifi_country, parse_country);
+        let sensor = loader.field(KEY_SENSOR, default.sensor, parse_sensor);
+        let device_id = loader.field(KEY_DEVICE_ID, default.device_id, parse_device_id);
+        let auth_token = loader.field(KEY_TOKEN, default.auth_token, parse_token);
+        let unit = loader.field(KEY_UNIT, default.unit, parse_unit);
+        let static_ip = loader.field(KEY_STATIC_IP, default.static_ip, parse_static_ip);
+        let ap_ssid = loader.field(KEY_AP_SSID, default.ap_ssid, parse_ap_ssid);
+        let ap_channel = loader.field(KEY_AP_CHANNEL, default.ap_channel, parse_ap_channel);
+        let ap_max_clients =
+            loader.field(KEY_AP_CLIENTS, default.ap_max_clients, parse_ap_clients);
+        let ap_net = loader.field(KEY_AP_NET, default.ap_net, ApNet::parse);
+        let dhcp_timeout =
+            loader.field(KEY_DHCP_TIMEOUT, default.dhcp_timeout, parse_dhcp_timeout);
+        let http_method = loader.field(KEY_HTTP_METHOD, default.http_method, parse_http_method);
+        let http_headers =
+            loader.field(KEY_HTTP_HEADERS, default.http_headers, parse_http_headers);
+        let encoding = loader.field(KEY_ENCODING, default.encoding, parse_encoding);
+        let analog_scale = loader.field(KEY_ANALOG_SCALE, default.analog_scale, parse_factor);
+        let analog_offset = loader.field(KEY_ANALOG_OFFSET, default.analog_offset, parse_factor);
+        let payload_template =
+            loader.field(KEY_TEMPLATE, default.payload_template, parse_template);
+        let log_level = loader.field(KEY_LOG_LEVEL, default.log_level, parse_log_level);
+        let quiet = loader.field(KEY_QUIET, default.quiet, parse_on_off);
+        let sd_log = loader.field(KEY_SD_LOG, default.sd_log, parse_sd_log);
+        let wifi_rebuild = loader.field(KEY_WIFI_REBUILD, default.wifi_rebuild, parse_wifi_rebuild);
+
+        Self {
+            url,
+            fallback_urls,
+            sample_interval,
+            report_interval,
+            heartbeat_interval,
+            dead_man,
+            schedule,
+            wifi_country,
+            sensor,
+            device_id,
+            auth_token,
+            unit,
+            static_ip,
+            ap_ssid,
+            ap_channel,
+            ap_max_clients,
+            ap_net,
+            dhcp_timeout,
+            http_method,
+            http_headers,
+            encoding,
+            analog_scale,
+            analog_offset,
+            payload_template,
+            log_level,
+            quiet,
+            sd_log,
+            wifi_rebuild,
+            warnings: loader.warnings,
+        }
+    }
+
+    /// Every field by its `store` name, in the form `store` takes. Unset
+    /// optional fields are left out and the token shows as `REDACTED`.
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let secs = |d: Option<Duration>| d.map_or(0, |d| d.as_secs()).to_string();
+        let headers: Vec<_> =
+            self.http_headers.iter().map(|(name, value)| format!("{name}: {value}")).collect();
+        let mut settings = vec![
+            ("url", self.url.clone()),
+            ("fallback_urls", self.fallback_urls.join(",")),
+            ("interval", self.sample_interval.as_secs().to_string()),
+            ("report_interval", secs(self.report_interval)),
+            ("heartbeat", secs(self.heartbeat_interval)),
+            ("dead_man", self.dead_man.map_or(0, |d| d.as_secs() / 60).to_string()),
+            ("schedule", self.schedule.clone()),
+            ("country", self.wifi_country.clone()),
+            ("sensor", self.sensor.name().to_ascii_lowercase()),
+            ("unit", self.unit.symbol().to_owned()),
+            ("http_method", method_name(self.http_method).to_owned()),
+            ("http_headers", headers.join("; ")),
+            ("encoding", self.encoding.name().to_owned()),
+            ("analog_scale", self.analog_scale.to_string()),
+            ("analog_offset", self.analog_offset.to_string()),
+            ("log_level", level_name(self.log_level).to_owned()),
+            ("quiet", if self.quiet { "on" } else { "off" }.to_owned()),
+            ("sd_log", if self.sd_log { "on" } else { "off" }.to_owned()),
+            ("wifi_rebuild", self.wifi_rebuild.unwrap_or(0).to_string()),
+            ("ap_ssid", self.ap_ssid.clone()),
+            ("ap_channel", self.ap_channel.to_string()),
+            ("ap_max_clients", self.ap_max_clients.to_string()),
+            ("ap_net", self.ap_net.to_string()),
+            ("dhcp_timeout", self.dhcp_timeout.as_secs().to_string()),
+        ];
+        if let Some(id) = &self.device_id {
+            settings.push(("device_id", id.clone()));
+        }
+        if self.auth_token.is_some() {
+            settings.push(("token", REDACTED.to_owned()));
+        }
+        if let Some(static_ip) = self.static_ip {
+            settings.push(("static_ip", static_ip.to_string()));
+        }
+        if let Some(template) = &self.payload_template {
+            settings.push(("template", template.clone()));
+        }
+        settings
+    }
+
+    /// Validates and stores one field by name; it takes effect on the next boot.
+    pub fn store(nvs: &mut EspNvs<NvsDefault>, name: &str, value: &str) -> Result<(), String> {
+        let key = Self::check(name, value)?;
+        persist::write_verified(nvs, key, value).map_err(|e| format!("NVS write failed: {:?}", e))
+    }
+
+    /// Stores every field that differs from its default and removes the rest,
+    /// so a later firmware's defaults still reach them. A round trip through
+    /// `load` gives back the same settings.
+    pub fn save(&self, nvs: &mut EspNvs<NvsDefault>) -> Result<(), String> {
+        let default = Self::default();
+        let defaults = default.settings();
+        let settings = self.settings();
+        for (name, value) in &settings {
+            let (value, is_default) = match (*name, &self.auth_token) {
+                ("token", Some(token)) => (token.as_str(), self.auth_token == default.auth_token),
+                _ => (value.as_str(), defaults.iter().any(|(n, v)| n == name && v == value)),
+            };
+            if is_default {
+                Self::clear(nvs, name)?;
+            } else {
+                Self::store(nvs, name, value)?;
+            }
+        }
+        // Optional fields left unset are not in `settings`
+        for name in ["device_id", "token", "static_ip", "template"] {
+            if !settings.iter().any(|(n, _)| *n == name) {
+                Self::clear(nvs, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a stored field by name, so its default applies from the next load.
+    pub fn clear(nvs: &mut EspNvs<NvsDefault>, name: &str) -> Result<(), String> {
+        let (key, _) = Self::field(name)?;
+        nvs.remove(key).map(drop).map_err(|e| format!("NVS remove failed: {:?}", e))
+    }
+
+    /// Validates one field by name without storing it; returns its NVS key.
+    pub fn check(name: &str, value: &str) -> Result<&'static str, String> {
+        let (key, check) = Self::field(name)?;
+        check(value)?;
+        Ok(key)
+    }
+
+    /// NVS key and validator of the field called `name`.
+    fn field(name: &str) -> Result<(&'static str, fn(&str) -> Result<(), String>), String> {
+        let field: (_, fn(&str) -> Result<(), String>) = match name {
+            "url" => (KEY_URL, |v| parse_url(v).map(drop)),
+            "fallback_urls" => (KEY_FALLBACK_URLS, |v| parse_fallback_urls(v).map(drop)),
+            "interval" => (KEY_INTERVAL, |v| parse_interval(v).map(drop)),
+            "report_interval" => (KEY_REPORT, |v| parse_report_interval(v).map(drop)),
+            "heartbeat" => (KEY_HEARTBEAT, |v| parse_report_interval(v).map(drop)),
+            "dead_man" => (KEY_DEAD_MAN, |v| parse_dead_man(v).map(drop)),
+            "schedule" => (KEY_SCHEDULE, |v| parse_schedule(v).map(drop)),
+            "country" => (KEY_COUNTRY, |v| parse_country(v).map(drop)),
+            "sensor" => (KEY_SENSOR, |v| parse_sensor(v).map(drop)),
+            "device_id" => (KEY_DEVICE_ID, |v| parse_device_id(v).map(drop)),
+            "token" => (KEY_TOKEN, |v| parse_token(v).map(drop)),
+            "unit" => (KEY_UNIT, |v| parse_unit(v).map(drop)),
+            "static_ip" => (KEY_STATIC_IP, |v| parse_static_ip(v).map(drop)),
+            "ap_ssid" => (KEY_AP_SSID, |v| parse_ap_ssid(v).map(drop)),
+            "ap_channel" => (KEY_AP_CHANNEL, |v| parse_ap_channel(v).map(drop)),
+            "ap_max_clients" => (KEY_AP_CLIENTS, |v| parse_ap_clients(v).map(drop)),
+            "ap_net" => (KEY_AP_NET, |v| ApNet::parse(v).map(drop)),
+            "dhcp_timeout" => (KEY_DHCP_TIMEOUT, |v| parse_dhcp_timeout(v).map(drop)),
+            "http_method" => (KEY_HTTP_METHOD, |v| parse_http_method(v).map(drop)),
+            "http_headers" => (KEY_HTTP_HEADERS, |v| parse_http_headers(v).map(drop)),
+            "encoding" => (KEY_ENCODING, |v| parse_encoding(v).map(drop)),
+            "analog_scale" => (KEY_ANALOG_SCALE, |v| parse_factor(v).map(drop)),
+            "analog_offset" => (KEY_ANALOG_OFFSET, |v| parse_factor(v).map(drop)),
+            "template" => (KEY_TEMPLATE, |v| parse_template(v).map(drop)),
+            "log_level" => (KEY_LOG_LEVEL, |v| parse_log_level(v).map(drop)),
+            "quiet" => (KEY_QUIET, |v| parse_on_off(v).map(drop)),
+            "sd_log" => (KEY_SD_LOG, |v| parse_sd_log(v).map(drop)),
+            "wifi_rebuild" => (KEY_WIFI_REBUILD, |v| parse_wifi_rebuild(v).map(drop)),
+            other => return Err(format!("unknown config field '{other}'")),
+        };
+        Ok(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssid_length_in_bytes() {
+        assert!(validate_ssid("").is_err());
+        assert!(validate_ssid("a").is_ok());
+        assert!(validate_ssid(&"a".repeat(32)).is_ok());
+        let err = validate_ssid(&"a".repeat(33)).unwrap_err();
+        assert_eq!(err, "SSID is 33 bytes, the limit is 32");
+    }
+
+    #[test]
+    fn multibyte_ssid_counts_bytes() {
+        // 16 two-byte characters fill the 32 bytes, one more ASCII letter is too many
+        assert!(validate_ssid(&"é".repeat(16)).is_ok());
+        assert!(validate_ssid(&format!("{}a", "é".repeat(16))).is_err());
+        // Only 11 characters, but 33 bytes
+        let err = validate_ssid(&"€".repeat(11)).unwrap_err();
+        assert_eq!(err, "SSID is 33 bytes, the limit is 32");
+    }
+
+    #[test]
+    fn ssid_without_nul() {
+        assert!(validate_ssid("home\0net").is_err());
+    }
+
+    #[test]
+    fn keys_fit_in_nvs() {
+        // NVS refuses keys over 15 bytes, on store and on load alike
+        let names = Config::default().settings().into_iter().map(|(name, _)| name);
+        for name in names.chain(["device_id", "token", "static_ip", "template"]) {
+            let (key, _) = Config::field(name).unwrap();
+            assert!(key.len() <= 15, "'{key}' is {} bytes", key.len());
+        }
+    }
+
+    #[test]
+    fn setup_ap_and_join_bounds() {
+        assert!(Config::check("ap_channel", "6").is_ok());
+        assert!(Config::check("ap_channel", "0").is_err());
+        assert!(Config::check("ap_channel", "15").is_err());
+        assert!(Config::check("ap_max_clients", "10").is_ok());
+        assert!(Config::check("ap_max_clients", "0").is_err());
+        assert!(Config::check("dhcp_timeout", "4").is_err());
+        assert_eq!(Config::check("dhcp_timeout", "120"), Ok(KEY_DHCP_TIMEOUT));
+        assert!(Config::check("ap_ssid", &"a".repeat(33)).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use std::time::{Duration, Instant};
+
+const KEY_FAILING_SECS: &str = "dm_failing";
+const KEY_SETUP_ONCE: &str = "dm_setup";
+
+/// Time spent without a successful post while Wi-Fi was up. Kept in NVS, so
+/// the restarts after repeated failures don't start it over; only awake time
+/// counts.
+pub struct DeadMan {
+    timeout: Duration,
+    failing: Duration,
+    // When `failing` was last brought up to date; None until a failure is seen
+    since: Option<Instant>,
+}
+
+impl DeadMan {
+    pub fn load(nvs: &EspNvs<NvsDefault>, timeout: Duration) -> Self {
+        let secs = nvs.get_u32(KEY_FAILING_SECS).ok().flatten().unwrap_or(0);
+        Self { timeout, failing: Duration::from_secs(secs.into()), since: None }
+    }
+
+    /// A post went through, or Wi-Fi itself was down: either way the server
+    /// is not the problem, so the count starts over.
+    pub fn reset(&mut self, nvs: &mut EspNvs<NvsDefault>) {
+        let counting = self.since.take().is_some() || !self.failing.is_zero();
+        self.failing = Duration::ZERO;
+        // Only written when something changes, posts succeed all the time
+        if counting {
+            if let Err(e) = nvs.remove(KEY_FAILING_SECS) {
+                log::warn!("Cannot clear the dead-man timer: {:?}", e);
+            }
+        }
+    }
+
+    /// A due flush delivered nothing with Wi-Fi up. True once that has gone on
+    /// for the whole timeout.
+    pub fn failed(&mut self, nvs: &mut EspNvs<NvsDefault>, now: Instant) -> bool {
+        if let Some(since) = self.since {
+            self.failing += now.saturating_duration_since(since);
+        }
+        self.since = Some(now);
+        let secs = self.failing.as_secs().min(u32::MAX as u64) as u32;
+        if let Err(e) = nvs.set_u32(KEY_FAILING_SECS, secs) {
+            log::warn!("Cannot store the dead-man timer: {:?}", e);
+        }
+        self.failing >= self.timeout
+    }
+
+    pub fn failing(&self) -> Duration {
+        self.failing
+    }
+}
+
+/// Has the next boot open the setup page instead of joining, keeping the
+/// stored network for the boot after. The timer starts over.
+pub fn request_setup(nvs: &mut EspNvs<NvsDefault>) {
+    let stored = nvs.set_u8(KEY_SETUP_ONCE, 1).and_then(|_| nvs.remove(KEY_FAILING_SECS));
+    if let Err(e) = stored {
+        log::warn!("Cannot schedule setup mode: {:?}", e);
+    }
+}
+
+/// True on the first boot after `request_setup`, and only that one.
+pub fn take_setup_request(nvs: &mut EspNvs<NvsDefault>) -> bool {
+    let requested = nvs.get_u8(KEY_SETUP_ONCE).ok().flatten().is_some();
+    if requested {
+        if let Err(e) = nvs.remove(KEY_SETUP_ONCE) {
+            log::warn!("Cannot clear the setup request: {:?}", e);
+        }
+    }
+    requested
+}
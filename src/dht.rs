@@ -0,0 +1,196 @@
+use std::fmt;
+
+/// Raw DHT frame: humidity, humidity fraction, temperature, temperature
+/// fraction, checksum. The DHT22 uses each pair as one big-endian value in
+/// tenths instead.
+pub type Frame = [u8; 5];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SensorKind {
+    Dht11,
+    Dht22,
+}
+
+impl SensorKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "dht11" => Some(Self::Dht11),
+            "dht22" => Some(Self::Dht22),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Dht11 => "DHT11",
+            Self::Dht22 => "DHT22",
+        }
+    }
+
+    /// How long the host holds the line low to start a read.
+    pub fn start_ms(self) -> u32 {
+        match self {
+            Self::Dht11 => 18,
+            Self::Dht22 => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Timeout,
+    ChecksumMismatch,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Timeout => f.write_str("line timeout"),
+            FrameError::ChecksumMismatch => f.write_str("checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dht11Reading {
+    pub temperature: i8,
+    pub humidity: u8,
+}
+
+pub fn checksum_ok(frame: &Frame) -> bool {
+    frame[..4].iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == frame[4]
+}
+
+// DHT11s that read below zero flag it with bit 7 of the fraction byte; some
+// clones use bit 7 of the temperature byte, as the DHT22 does
+fn dht11_negative(frame: &Frame) -> bool {
+    (frame[2] | frame[3]) & 0x80 != 0
+}
+
+/// Whole degrees, rounded towards zero: -3.4 °C reads as -3.
+pub fn decode(frame: &Frame) -> Dht11Reading {
+    let magnitude = (frame[2] & 0x7F) as i8;
+    let temperature = if dht11_negative(frame) { -magnitude } else { magnitude };
+    Dht11Reading { temperature, humidity: frame[0] }
+}
+
+/// Temperature (°C) and humidity (%) of a frame that passed its checksum.
+pub fn decode_as(kind: SensorKind, frame: &Frame) -> (f32, f32) {
+    match kind {
+        SensorKind::Dht11 => {
+            // Sign and magnitude, the fraction in tenths (always 0 on older parts)
+            let magnitude = (frame[2] & 0x7F) as f32 + (frame[3] & 0x0F) as f32 / 10.0;
+            let temperature = if dht11_negative(frame) { -magnitude } else { magnitude };
+            (temperature, frame[0] as f32)
+        }
+        SensorKind::Dht22 => {
+            let humidity = u16::from_be_bytes([frame[0], frame[1]]) as f32 / 10.0;
+            let magnitude = u16::from_be_bytes([frame[2] & 0x7F, frame[3]]) as f32 / 10.0;
+            let temperature = if frame[2] & 0x80 != 0 { -magnitude } else { magnitude };
+            (temperature, humidity)
+        }
+    }
+}
+
+// A corrected frame must look like something a DHT11 really sends: datasheet
+// range (-20 to 60 °C on parts that go below zero), no humidity fraction, and
+// close to the last good reading if any.
+fn plausible(frame: &Frame, last: Option<Dht11Reading>) -> bool {
+    if frame[1] != 0 || frame[3] & 0x7F > 9 {
+        return false;
+    }
+    let reading = decode(frame);
+    if !(-20..=60).contains(&reading.temperature) || !(20..=90).contains(&reading.humidity) {
+        return false;
+    }
+    match last {
+        Some(last) => {
+            (reading.temperature - last.temperature).abs() <= 2
+                && (reading.humidity as i16 - last.humidity as i16).abs() <= 5
+        }
+        None => true,
+    }
+}
+
+/// For a frame that failed its checksum, returns the first single-bit flip
+/// that passes both the checksum and the plausibility gate. Gives up when
+/// another passing flip would decode to a different reading.
+pub fn correct_single_bit(frame: &Frame, last: Option<Dht11Reading>) -> Option<Frame> {
+    let mut candidates = (0..40).filter_map(|bit| {
+        let mut candidate = *frame;
+        candidate[bit / 8] ^= 0x80 >> (bit % 8);
+        (checksum_ok(&candidate) && plausible(&candidate, last)).then_some(candidate)
+    });
+    let first = candidates.next()?;
+    candidates
+        .all(|other| decode(&other) == decode(&first))
+        .then_some(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 45 % and 22 °C, checksum 67
+    const CLEAN: Frame = [45, 0, 22, 0, 67];
+    const LAST: Option<Dht11Reading> = Some(Dht11Reading { temperature: 22, humidity: 45 });
+
+    fn flipped(bits: &[(usize, u8)]) -> Frame {
+        let mut frame = CLEAN;
+        for &(byte, mask) in bits {
+            frame[byte] ^= mask;
+        }
+        frame
+    }
+
+    #[test]
+    fn clean_frame_needs_no_correction() {
+        assert!(checksum_ok(&CLEAN));
+        // Any single flip of a good frame breaks its checksum
+        assert_eq!(correct_single_bit(&CLEAN, LAST), None);
+    }
+
+    #[test]
+    fn corrects_one_flip_in_any_byte() {
+        for byte in 0..5 {
+            let frame = flipped(&[(byte, 0x40)]);
+            assert!(!checksum_ok(&frame));
+            assert_eq!(correct_single_bit(&frame, LAST), Some(CLEAN), "byte {byte}");
+        }
+    }
+
+    #[test]
+    fn gives_up_on_ambiguous_flip() {
+        // Read as 45 % 23 °C: either 45 % 22 °C or 44 % 23 °C with one bit back
+        assert_eq!(correct_single_bit(&flipped(&[(2, 0x01)]), LAST), None);
+    }
+
+    #[test]
+    fn rejects_two_flips() {
+        for bits in [[(0, 0x40), (2, 0x04)], [(1, 0x01), (4, 0x80)], [(2, 0x10), (3, 0x02)]] {
+            let frame = flipped(&bits);
+            assert!(!checksum_ok(&frame));
+            assert_eq!(correct_single_bit(&frame, LAST), None, "{bits:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_negative_temperatures() {
+        // DHT22: sign bit on the temperature word, 123 tenths; 40.0 %
+        let frame = [0x01, 0x90, 0x80, 0x7B, 0x8C];
+        assert!(checksum_ok(&frame));
+        let (t, h) = decode_as(SensorKind::Dht22, &frame);
+        assert_eq!((t, h), (-12.3, 40.0));
+        assert_eq!(serde_json::to_string(&t).unwrap(), "-12.3");
+        // DHT11: sign on the fraction byte, or on the temperature byte with some clones
+        for frame in [[40, 0, 12, 0x83, 0xB7], [40, 0, 0x8C, 0x03, 0xB7]] {
+            assert!(checksum_ok(&frame));
+            let (t, h) = decode_as(SensorKind::Dht11, &frame);
+            assert_eq!((t, h), (-12.3, 40.0));
+            assert_eq!(serde_json::to_string(&t).unwrap(), "-12.3");
+            assert_eq!(decode(&frame).temperature, -12);
+        }
+    }
+}
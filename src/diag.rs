@@ -0,0 +1,192 @@
+use anyhow::Result;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::server::{Configuration as ServerConfig, EspHttpServer};
+use esp_idf_svc::io::Write;
+use crate::history::History;
+use crate::ota::OtaState;
+use crate::sensor::{Measurement, ReadErrors};
+use embedded_svc::ipv4::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Added to twice the expected gap, for a slow read or a retried post
+const FRESH_SLACK: Duration = Duration::from_secs(30);
+
+/// How old the last post and the last read may get before /healthz reports
+/// 503: twice the gap expected between them, plus `FRESH_SLACK`.
+#[derive(Clone, Copy, Debug)]
+pub struct Freshness {
+    pub post: Duration,
+    pub read: Duration,
+}
+
+impl Freshness {
+    pub fn new(read_every: Duration, post_every: Duration) -> Self {
+        Self { post: post_every * 2 + FRESH_SLACK, read: read_every * 2 + FRESH_SLACK }
+    }
+}
+
+impl Default for Freshness {
+    fn default() -> Self {
+        Self::new(crate::SAMPLE_INTERVAL, crate::SAMPLE_INTERVAL)
+    }
+}
+
+#[derive(Default)]
+pub struct Health {
+    pub connected: bool,
+    pub last_post_ok: Option<Instant>,
+    pub last_read_ok: Option<Instant>,
+    pub ota: OtaState,
+    pub ssid: Option<String>,
+    pub ip: Option<Ipv4Addr>,
+    pub last_reading: Vec<Measurement>,
+    // Status the server gave the last post that got an answer
+    pub last_post_status: Option<u16>,
+    pub boot_count: u32,
+    pub reset_reason: &'static str,
+    pub read_errors: ReadErrors,
+    pub history: History,
+    pub freshness: Freshness,
+}
+
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+fn fresh(at: Option<Instant>, now: Instant, max_age: Duration) -> bool {
+    at.is_some_and(|t| now.duration_since(t) <= max_age)
+}
+
+impl Health {
+    /// Ready only when connected with a recent post and a recent good read.
+    pub fn check(&self, now: Instant) -> Result<(), &'static str> {
+        if !self.connected {
+            return Err("wifi disconnected");
+        }
+        if !fresh(self.last_post_ok, now, self.freshness.post) {
+            return Err("no recent successful post");
+        }
+        if !fresh(self.last_read_ok, now, self.freshness.read) {
+            return Err("no recent sensor reading");
+        }
+        Ok(())
+    }
+}
+
+pub fn spawn_diag_server(health: SharedHealth) -> Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&ServerConfig::default())?;
+
+    server.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
+        let mut r = req.into_ok_response()?;
+        r.write_all(br#"<!doctype html><html><body>
+<h3>ESP32 mk2</h3>
+<ul><li><a href=/healthz>Health</a></li><li><a href=/ota/status>OTA status</a></li>
+<li><a href=/nvs/status>NVS status</a></li><li><a href=/status>Status</a></li>
+<li><a href=/history>History</a></li></ul>
+</body></html>"#)?;
+        Ok(())
+    })?;
+
+    let ota_health = health.clone();
+    server.fn_handler("/ota/status", Method::Get, move |req| -> anyhow::Result<()> {
+        let ota = ota_health.lock().unwrap().ota.clone();
+        let body = serde_json::json!({
+            "state": ota.as_str(),
+            "detail": ota.detail(),
+            "signature": "ed25519",
+        });
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(body.to_string().as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/nvs/status", Method::Get, |req| -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "verify": crate::NVS_VERIFY,
+            "verify_failures": crate::persist::verify_failures(),
+        });
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(body.to_string().as_bytes())?;
+        Ok(())
+    })?;
+
+    let status_health = health.clone();
+    server.fn_handler("/status", Method::Get, move |req| -> anyhow::Result<()> {
+        // The low-water mark shows how close the heap came to CRITICAL_HEAP
+        let min_free_heap = unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() };
+        log::info!("Min-ever free heap {min_free_heap} B");
+        let body = {
+            let health = status_health.lock().unwrap();
+            let reading: Vec<_> = health
+                .last_reading
+                .iter()
+                .map(|m| {
+                    serde_json::json!({
+                        "tag": m.tag,
+                        "temperature": m.temperature,
+                        "humidity": m.humidity,
+                    })
+                })
+                .collect();
+            serde_json::json!({
+                "ssid": health.ssid,
+                "ip": health.ip.map(|ip| ip.to_string()),
+                "uptime_ms": unsafe { esp_idf_sys::esp_timer_get_time() } / 1000,
+                "last_reading": reading,
+                "last_post_status": health.last_post_status,
+                "free_heap": unsafe { esp_idf_sys::esp_get_free_heap_size() },
+                "min_free_heap": min_free_heap,
+                "boot_count": health.boot_count,
+                "reset_reason": health.reset_reason,
+                "dht_timeouts": health.read_errors.timeouts,
+                "dht_checksum_errors": health.read_errors.checksum_mismatches,
+                "implausible_readings": health.read_errors.implausible,
+            })
+        };
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(body.to_string().as_bytes())?;
+        Ok(())
+    })?;
+
+    // `?n=10` for the newest 10 only; more than are kept is not an error
+    let history_health = health.clone();
+    server.fn_handler("/history", Method::Get, crate::guarded(move |req| {
+        let query = req.uri().split_once('?').map_or("", |(_, query)| query);
+        let n = query.split('&').find_map(|pair| pair.strip_prefix("n="));
+        let n = match n.map(str::parse::<usize>) {
+            None => None,
+            Some(Ok(n)) => Some(n),
+            Some(Err(_)) => {
+                return crate::reply_error(req, 400, "Bad Request", "n must be a whole number");
+            }
+        };
+        let body = {
+            let health = history_health.lock().unwrap();
+            let cap = health.history.capacity();
+            health.history.to_json(n.unwrap_or(cap).min(cap))
+        };
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(body.to_string().as_bytes())?;
+        Ok(())
+    }))?;
+
+    server.fn_handler("/healthz", Method::Get, move |req| -> anyhow::Result<()> {
+        let verdict = health.lock().unwrap().check(Instant::now());
+        match verdict {
+            Ok(()) => {
+                let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?;
+                r.write_all(b"ok")?;
+            }
+            Err(reason) => {
+                let mut r = req.into_response(
+                    503,
+                    Some("Service Unavailable"),
+                    &[("Content-Type", "text/plain")],
+                )?;
+                r.write_all(reason.as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(server)
+}
@@ -0,0 +1,69 @@
+use crate::config;
+use crate::transport::http_config;
+use anyhow::{anyhow, Result};
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use serde::Deserialize;
+
+const NVS_KEY: &str = "endpoint";
+const MAX_BODY: usize = 1024;
+
+#[derive(Deserialize)]
+struct Discovery {
+    endpoint: String,
+}
+
+fn fetch_endpoint(discovery_url: &str) -> Result<String> {
+    let conn = EspHttpConnection::new(&http_config(discovery_url))?;
+    let mut client = Client::wrap(conn);
+    let mut resp = client.get(discovery_url)?.submit()?;
+    if resp.status() != 200 {
+        return Err(anyhow!("Discovery HTTP {}", resp.status()));
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = resp.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+        if body.len() > MAX_BODY {
+            return Err(anyhow!("Discovery body too large"));
+        }
+    }
+
+    let Discovery { endpoint } = serde_json::from_slice(&body)?;
+    if !config::valid_url(&endpoint) {
+        return Err(anyhow!("Invalid endpoint '{endpoint}'"));
+    }
+    Ok(endpoint)
+}
+
+pub fn cached_endpoint(nvs: &EspNvs<NvsDefault>) -> Option<String> {
+    let mut buf = [0u8; 256];
+    nvs.get_str(NVS_KEY, &mut buf).ok().flatten().map(str::to_owned)
+}
+
+/// Asks the discovery URL for the current telemetry endpoint, falling back to
+/// the last-known one stored in NVS when the lookup fails.
+pub fn discover_endpoint(nvs: &mut EspNvs<NvsDefault>, discovery_url: &str) -> Option<String> {
+    match fetch_endpoint(discovery_url) {
+        Ok(endpoint) => {
+            // Only write on change to spare the flash
+            if cached_endpoint(nvs).as_deref() != Some(endpoint.as_str()) {
+                log::info!("🔎 Discovered endpoint: {endpoint}");
+                if let Err(e) = nvs.set_str(NVS_KEY, &endpoint) {
+                    log::warn!("Cannot cache endpoint: {:?}", e);
+                }
+            }
+            Some(endpoint)
+        }
+        Err(e) => {
+            log::warn!("Discovery failed: {:?}", e);
+            cached_endpoint(nvs)
+        }
+    }
+}
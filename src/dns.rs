@@ -0,0 +1,170 @@
+use anyhow::Result;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often the responder looks at its stop flag between queries
+const POLL: Duration = Duration::from_millis(500);
+const TTL_SECS: u32 = 60;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Reply to a DNS query pointing every A record at `ip`; other types get an
+/// empty answer so clients fall back to A. `None` for anything that is not a
+/// standard query.
+pub fn answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    // Header: id, flags, then question/answer/authority/additional counts
+    if query.len() < 12 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    // Responses and anything but opcode 0 (QUERY) are left alone
+    if flags & 0x8000 != 0 || flags & 0x7800 != 0 || qdcount == 0 {
+        return None;
+    }
+
+    // Only the first question is answered, which is all resolvers send
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers never appear in a question we need to read
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(end + 2)?, *query.get(end + 3)?]);
+    end += 4;
+    let answered = (qtype == TYPE_A || qtype == TYPE_ANY) && qclass == CLASS_IN;
+
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // QR + AA, keeping the client's RD bit
+    reply.extend_from_slice(&(0x8400 | (flags & 0x0100)).to_be_bytes());
+    reply.extend_from_slice(&1u16.to_be_bytes());
+    reply.extend_from_slice(&(answered as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..end]);
+    if answered {
+        // Name as a pointer back to the question at offset 12
+        reply.extend_from_slice(&[0xC0, 0x0C]);
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&TTL_SECS.to_be_bytes());
+        reply.extend_from_slice(&4u16.to_be_bytes());
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+/// Resolves every name to the setup AP so phones notice a captive portal and
+/// open the setup page on their own. Stops when dropped.
+pub struct CaptiveDns {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for CaptiveDns {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts the responder on port 53 of `ip`. Bound to that address only, so
+/// it never answers on the station side.
+pub fn spawn(ip: Ipv4Addr) -> Result<CaptiveDns> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(ip, 53))?;
+    socket.set_read_timeout(Some(POLL))?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = thread::Builder::new()
+        .name("dns".into())
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            let mut buf = [0u8; 512];
+            while !stopped.load(Ordering::Relaxed) {
+                // Timeouts land here too, and just recheck the flag
+                let Ok((n, peer)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if let Some(reply) = answer(&buf[..n], ip) {
+                    let _ = socket.send_to(&reply, peer);
+                }
+            }
+        })?;
+    Ok(CaptiveDns { stop, thread: Some(thread) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// A standard query with RD set for `example.com` of type `qtype`.
+    fn query(qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        q.extend_from_slice(b"\x07example\x03com\x00");
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&CLASS_IN.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn answers_a_with_the_ap_address() {
+        let q = query(TYPE_A);
+        let reply = answer(&q, AP).unwrap();
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        // QR + AA, RD kept; one question, one answer
+        assert_eq!(reply[2..12], [0x85, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reply[12..q.len()], q[12..]);
+        let record = &reply[q.len()..];
+        assert_eq!(record[..2], [0xC0, 0x0C]);
+        assert_eq!(record[2..6], [0, 1, 0, 1]);
+        assert_eq!(record[6..10], TTL_SECS.to_be_bytes());
+        assert_eq!(record[10..], [0, 4, 192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn aaaa_gets_an_empty_answer() {
+        let q = query(28);
+        let reply = answer(&q, AP).unwrap();
+        assert_eq!(reply[2..12], [0x85, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reply.len(), q.len());
+    }
+
+    #[test]
+    fn ignores_responses_and_other_opcodes() {
+        let mut response = query(TYPE_A);
+        response[2] |= 0x80;
+        assert_eq!(answer(&response, AP), None);
+        // Opcode 2 (STATUS)
+        let mut status = query(TYPE_A);
+        status[2] |= 0x10;
+        assert_eq!(answer(&status, AP), None);
+    }
+
+    #[test]
+    fn ignores_malformed_questions() {
+        let q = query(TYPE_A);
+        assert_eq!(answer(&q[..11], AP), None);
+        assert_eq!(answer(&q[..q.len() - 1], AP), None);
+        // Label longer than the packet
+        assert_eq!(answer(&q[..16], AP), None);
+        let mut compressed = q[..12].to_vec();
+        compressed.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+        assert_eq!(answer(&compressed, AP), None);
+    }
+}
@@ -0,0 +1,77 @@
+use esp_idf_sys::EspError;
+
+// The supplicant refuses longer identities and usernames
+const MAX_LEN: usize = 127;
+
+/// WPA2-Enterprise login for PEAP or EAP-TTLS, MSCHAPv2 inside either; the
+/// supplicant follows whichever the network offers. Certificate-based EAP-TLS
+/// is not supported.
+#[derive(Clone)]
+pub struct EapCreds {
+    /// Outer identity, sent before the tunnel is up, e.g. `anonymous@campus.edu`
+    pub identity: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl EapCreds {
+    /// `None` without a username, which leaves the network to PSK or open auth.
+    /// A blank identity falls back to the username.
+    pub fn from_fields(
+        identity: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Option<Self> {
+        let username = username.filter(|user| !user.is_empty())?;
+        let identity = identity.filter(|id| !id.is_empty()).unwrap_or_else(|| username.clone());
+        Some(Self { identity, username, password: password.unwrap_or_default() })
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if !cfg!(feature = "enterprise") {
+            return Err("WPA2-Enterprise needs a build with the enterprise feature".into());
+        }
+        for (name, value) in
+            [("identity", &self.identity), ("username", &self.username), ("password", &self.password)]
+        {
+            if value.is_empty() || value.len() > MAX_LEN {
+                return Err(format!("enterprise {name} must be 1-{MAX_LEN} bytes"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hands `creds` to the supplicant, or switches enterprise auth off for a PSK
+/// or open network. Goes between setting the station config and connecting.
+#[cfg(feature = "enterprise")]
+pub fn apply(creds: Option<&EapCreds>) -> Result<(), EspError> {
+    use esp_idf_sys::{self as sys, esp};
+
+    let Some(creds) = creds else {
+        // Otherwise the last enterprise login keeps being offered
+        return esp!(unsafe { sys::esp_wifi_sta_wpa2_ent_disable() });
+    };
+    // Lengths are checked against MAX_LEN before anything gets here
+    unsafe {
+        esp!(sys::esp_wifi_sta_wpa2_ent_set_identity(
+            creds.identity.as_ptr(),
+            creds.identity.len() as i32
+        ))?;
+        esp!(sys::esp_wifi_sta_wpa2_ent_set_username(
+            creds.username.as_ptr(),
+            creds.username.len() as i32
+        ))?;
+        esp!(sys::esp_wifi_sta_wpa2_ent_set_password(
+            creds.password.as_ptr(),
+            creds.password.len() as i32
+        ))?;
+        esp!(sys::esp_wifi_sta_wpa2_ent_enable())
+    }
+}
+
+/// Nothing to switch off: without the feature enterprise auth is never enabled.
+#[cfg(not(feature = "enterprise"))]
+pub fn apply(_creds: Option<&EapCreds>) -> Result<(), EspError> {
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use esp_idf_svc::sys::EspError;
+use std::time::Duration;
+
+/// Failures the caller tells apart to pick a recovery: retry, rejoin,
+/// re-provision or reboot. Everything else stays `anyhow`.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    /// The Wi-Fi driver itself failed; only a reboot is likely to help
+    #[error("Wi-Fi driver: {0}")]
+    Wifi(#[from] EspError),
+    #[error("Wi-Fi still running after {0} stop attempts")]
+    WifiStuck(u32),
+    /// Settings the driver can't use, such as an over-long SSID
+    #[error("bad Wi-Fi settings: {0}")]
+    Config(String),
+    /// The AP never took the association, for no reason it gave: out of
+    /// range, or too busy
+    #[error("association failed")]
+    Association,
+    /// The AP turned the credentials down, with this disconnect reason
+    #[error("authentication rejected (reason {0})")]
+    Auth(u16),
+    /// Associated, but DHCP handed out no address in time
+    #[error("no DHCP lease within {0:?}")]
+    Dhcp(Duration),
+    /// The server answered, but not with a 2xx
+    #[error("server answered {0}")]
+    Http(u16),
+    /// The payload never got an answer
+    #[error("not delivered: {0}")]
+    Send(String),
+    /// Kept queued on purpose, e.g. until it can be signed; nothing failed
+    #[error("held back: {0}")]
+    Held(String),
+}
+
+impl AppError {
+    pub fn send(e: impl std::fmt::Debug) -> Self {
+        Self::Send(format!("{:?}", e))
+    }
+}
@@ -0,0 +1,52 @@
+use miniz_oxide::deflate::compress_to_vec;
+
+// Compression level, traded against time on the ESP32
+const LEVEL: u8 = 6;
+
+/// `data` as a gzip member (RFC 1952): a minimal header, the raw deflate
+/// stream, then CRC-32 and length of the input.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let deflated = compress_to_vec(data, LEVEL);
+    let mut out = Vec::with_capacity(deflated.len() + 18);
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+    out.extend_from_slice(&deflated);
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// CRC-32 (IEEE), bit by bit: payloads are small and a table costs 1 KB.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::inflate::decompress_to_vec;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn round_trips_through_inflate() {
+        let data = br#"[{"temperature":21.5,"humidity":40},{"temperature":21.6,"humidity":40}]"#;
+        let gz = compress(data);
+        assert_eq!(gz[..10], [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+        let (body, trailer) = gz[10..].split_at(gz.len() - 18);
+        assert_eq!(decompress_to_vec(body).unwrap(), data);
+        assert_eq!(trailer[..4], crc32(data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+    }
+}
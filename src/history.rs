@@ -0,0 +1,170 @@
+use crate::clock::Stamp;
+use crate::outbox::Settled;
+use crate::sensor::{Measurement, QUALITY_NO_SENSOR};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Queued,
+    Sent,
+    Dropped,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Sent => "sent",
+            Self::Dropped => "dropped",
+        }
+    }
+}
+
+struct Entry {
+    id: u32,
+    stamp: Stamp,
+    readings: Vec<Measurement>,
+    status: Status,
+}
+
+/// The last few samples, kept in RAM for `GET /history`. A sample is sent once
+/// a network sink got an ack for a payload carrying it, and dropped when such
+/// a payload was thrown away (poison or a full queue) before any ack.
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+    next_id: u32,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(crate::HISTORY_LEN)
+    }
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: VecDeque::with_capacity(capacity), capacity: capacity.max(1), next_id: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Id the next recorded sample gets; ids count up from 0 at boot.
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    /// Keeps a sample as queued and returns its id, for `settle`.
+    pub fn record(&mut self, stamp: Stamp, readings: &[Measurement]) -> u32 {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let readings = readings.to_vec();
+        self.entries.push_back(Entry { id, stamp, readings, status: Status::Queued });
+        id
+    }
+
+    /// Applies what one network sink did with its payloads. An ack from any
+    /// sink wins over a drop by another.
+    pub fn settle(&mut self, settled: &Settled) {
+        for samples in &settled.sent {
+            self.mark(samples, Status::Sent);
+        }
+        for samples in &settled.dropped {
+            self.mark(samples, Status::Dropped);
+        }
+    }
+
+    /// Marks samples no payload will ever carry, e.g. a report with nothing to average.
+    pub fn drop_samples(&mut self, samples: Range<u32>) {
+        self.mark(&samples, Status::Dropped);
+    }
+
+    fn mark(&mut self, samples: &Range<u32>, status: Status) {
+        for entry in self.entries.iter_mut().filter(|e| samples.contains(&e.id)) {
+            if entry.status != Status::Sent {
+                entry.status = status;
+            }
+        }
+    }
+
+    /// The newest `n` samples, oldest first.
+    pub fn to_json(&self, n: usize) -> Value {
+        let skip = self.entries.len().saturating_sub(n);
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .skip(skip)
+            .map(|entry| {
+                let readings: Vec<_> = entry
+                    .readings
+                    .iter()
+                    .map(|m| {
+                        // A sensor that did not answer has no values to show
+                        let answered = m.quality & QUALITY_NO_SENSOR == 0;
+                        serde_json::json!({
+                            "tag": m.tag,
+                            "temperature": answered.then_some(m.temperature),
+                            "humidity": m.humidity.filter(|_| answered),
+                            "quality": m.quality,
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "ts": entry.stamp.unix_ms,
+                    "uptime_ms": entry.stamp.uptime_ms,
+                    "readings": readings,
+                    "status": entry.status.as_str(),
+                })
+            })
+            .collect();
+        entries.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TimeSource;
+
+    fn history_with(samples: u32) -> History {
+        let mut history = History::new(10);
+        for i in 0..samples {
+            let stamp = Stamp { unix_ms: None, uptime_ms: i as u64, source: TimeSource::None };
+            assert_eq!(history.record(stamp, &[Measurement::default()]), i);
+        }
+        history
+    }
+
+    fn settled(sent: Range<u32>, dropped: Range<u32>) -> Settled {
+        Settled { sent: vec![sent], dropped: vec![dropped] }
+    }
+
+    fn statuses(history: &History) -> Vec<String> {
+        let json = history.to_json(history.capacity());
+        json.as_array().unwrap().iter().map(|e| e["status"].as_str().unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn only_acks_mark_samples_sent() {
+        let mut history = history_with(4);
+        history.settle(&settled(0..2, 2..3));
+        assert_eq!(statuses(&history), ["sent", "sent", "dropped", "queued"]);
+    }
+
+    #[test]
+    fn an_ack_from_any_sink_wins() {
+        let mut history = history_with(2);
+        history.settle(&settled(0..0, 0..2));
+        history.settle(&settled(0..1, 0..0));
+        assert_eq!(statuses(&history), ["sent", "dropped"]);
+        history.drop_samples(0..2);
+        assert_eq!(statuses(&history), ["sent", "dropped"]);
+    }
+}
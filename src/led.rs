@@ -0,0 +1,91 @@
+use esp_idf_hal::gpio::{AnyOutputPin, PinDriver};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Length of one step of a blink pattern
+const STEP: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedState {
+    Off,
+    /// Setup AP up, waiting for credentials
+    Setup,
+    Connecting,
+    /// Connected with a recent post and a recent sensor read
+    Online,
+    /// Connected, but posts or sensor reads are failing
+    Error,
+}
+
+impl LedState {
+    const ALL: [LedState; 5] = [Self::Off, Self::Setup, Self::Connecting, Self::Online, Self::Error];
+
+    /// On/off levels, one per `STEP`, repeated while the state holds.
+    fn pattern(self) -> &'static [bool] {
+        const X: bool = true;
+        const O: bool = false;
+        match self {
+            Self::Off => &[O],
+            Self::Setup => &[X, O],
+            Self::Connecting => &[X, X, X, X, X, O, O, O, O, O],
+            Self::Online => &[X],
+            Self::Error => &[X, O, X, O, O, O, O, O, O, O],
+        }
+    }
+}
+
+/// An LED showing the device state as a blink pattern, driven from its own
+/// thread. Without a usable pin every call is a no-op.
+pub struct StatusLed {
+    state: Option<Arc<AtomicU8>>,
+}
+
+impl StatusLed {
+    pub fn disabled() -> Self {
+        Self { state: None }
+    }
+
+    pub fn spawn(pin: AnyOutputPin) -> Self {
+        let mut led = match PinDriver::output(pin) {
+            Ok(led) => led,
+            Err(e) => {
+                log::warn!("State LED unavailable: {:?}", e);
+                return Self { state: None };
+            }
+        };
+        let state = Arc::new(AtomicU8::new(LedState::Off as u8));
+        let shown = state.clone();
+        let spawned = thread::Builder::new()
+            .name("led".into())
+            .stack_size(2 * 1024)
+            .spawn(move || {
+                let mut step = 0;
+                let mut current = LedState::Off;
+                loop {
+                    let wanted = LedState::ALL[shown.load(Ordering::Relaxed) as usize];
+                    // Start each new pattern from its first step
+                    if wanted != current {
+                        current = wanted;
+                        step = 0;
+                    }
+                    let pattern = current.pattern();
+                    let _ = led.set_level(pattern[step % pattern.len()].into());
+                    step += 1;
+                    thread::sleep(STEP);
+                }
+            });
+        if let Err(e) = spawned {
+            log::warn!("State LED thread failed to start: {:?}", e);
+            return Self { state: None };
+        }
+        Self { state: Some(state) }
+    }
+
+    pub fn set(&self, state: LedState) {
+        if let Some(shared) = &self.state {
+            shared.store(state as u8, Ordering::Relaxed);
+        }
+    }
+}
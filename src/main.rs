@@ -1,231 +1,2722 @@
+mod adaptive;
+mod aggregate;
+mod analog;
+mod backoff;
+mod backpressure;
+mod batch;
+mod battery;
+mod bootguard;
+mod charge;
+mod clock;
+mod commands;
+mod config;
+mod deadman;
+mod dht;
+mod diag;
+mod discovery;
+mod dns;
+mod eap;
+mod error;
+#[cfg(feature = "gzip")]
+mod gzip;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod history;
+mod led;
+mod metrics;
+mod ota;
+mod outbox;
+mod persist;
+mod reprovision;
+mod schedule;
+mod selftest;
+#[cfg(feature = "sdcard")]
+mod sdlog;
+mod sensor;
+mod signing;
+mod telemetry;
+mod transport;
+mod util;
+
 use anyhow::{anyhow, Context, Result};
 use embedded_svc::http::Method;
 use embedded_svc::ipv4::Ipv4Addr;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::http::server::{Configuration as ServerConfig, EspHttpServer};
-use esp_idf_svc::http::client::{Configuration as HttpCfg, EspHttpConnection};
+use esp_idf_svc::hal::reset::{ResetReason, WakeupReason};
+use embedded_svc::http::server::Request;
+use esp_idf_svc::http::server::{Configuration as ServerConfig, EspHttpConnection, EspHttpServer};
 use esp_idf_svc::io::Write;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::ipv4;
 use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
 use esp_idf_svc::wifi::{
-    AccessPointConfiguration as ApConfiguration, AuthMethod, ClientConfiguration,
-    Configuration as WifiConfiguration, EspWifi,
+    AccessPointConfiguration as ApConfiguration, AccessPointInfo, AuthMethod, ClientConfiguration,
+    Configuration as WifiConfiguration, EspWifi, WifiEvent,
 };
-use std::sync::mpsc::{channel, Sender};
+use esp_idf_sys::{self as sys, esp};
+use std::ffi::CString;
+use std::ops::{Range, RangeInclusive};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use std::{thread};
 
-use esp_idf_hal::{
-    delay::Ets,
-    gpio::{PinDriver, Pull}
+use esp_idf_hal::adc::oneshot::AdcDriver;
+use esp_idf_hal::gpio::{Gpio9, IOPin, OutputPin, Pin, PinDriver, Pull};
+use esp_idf_hal::task::watchdog::{TWDTConfig, TWDTDriver};
+use adaptive::Adaptive;
+use aggregate::Aggregator;
+use backoff::Backoff;
+use backpressure::{Policy, Producer};
+use batch::Batch;
+use analog::{Analog, AnalogReading};
+use battery::Battery;
+use clock::{Clock, Stamp};
+use commands::Command;
+use metrics::Metrics;
+use config::{ApNet, Config, StaticIp};
+use eap::EapCreds;
+use error::AppError;
+use dht::SensorKind;
+use diag::{Freshness, Health, SharedHealth};
+use led::{LedState, StatusLed};
+use outbox::{Outbox, Payload};
+use schedule::Schedule;
+use serde::{Deserialize, Serialize};
+use telemetry::{Encoding, FieldFilter, Telemetry, TempUnit, Template};
+use transport::{HttpTransport, MqttTransport, Signer, Sink, Transport};
+use util::parse_form;
+use sensor::{
+    plausible, Averaged, DhtSensor, Measurement, ReadErrors, Sensor, QUALITY_IMPLAUSIBLE,
+    QUALITY_NO_SENSOR,
+};
+
+const DEFAULT_URL: &str = "http://b15ca8fb2839.ngrok-free.app/ping";
+// Tried in order when the URL above gives no 2xx; whichever answers goes first
+// from then on. At most 3, http(s) only. The cfg_fallbacks NVS key
+// (comma-separated) overrides it.
+const FALLBACK_URLS: &[&str] = &[];
+// Stable URL answering {"endpoint":"..."}; None keeps DEFAULT_URL
+const DISCOVERY_URL: Option<&str> = None;
+const DISCOVERY_EVERY_CYCLES: u32 = 60;
+// Regulatory domain, e.g. "US" (ch 1-11), "FR" (1-13), "JP" (1-14)
+const WIFI_COUNTRY: &str = "01";
+// Station power save: WIFI_PS_NONE (lowest latency, highest current and TX
+// peaks), WIFI_PS_MIN_MODEM (sleeps between DTIM beacons) or WIFI_PS_MAX_MODEM
+// (sleeps longest, pings can take hundreds of ms)
+const WIFI_POWER_SAVE: sys::wifi_ps_type_t = sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM;
+// Preferred setup AP channel, replaced by the first allowed one if the country
+// forbids it; the cfg_ap_channel NVS key overrides it
+const AP_CHANNEL: u8 = 6;
+const CONFIG_MAX_BODY: usize = 8 * 1024;
+// The setup form is a few hundred bytes; anything far past that is refused
+const SETUP_MAX_BODY: usize = 4 * 1024;
+// Signed firmware image (detached signature at <url>.sig); None disables OTA
+const FIRMWARE_URL: Option<&str> = None;
+const OTA_EVERY_CYCLES: u32 = 360;
+// Long-polled for {"cmd": "relay" | "reboot" | "post_now" | "config", ...}; None disables it
+const COMMANDS_URL: Option<&str> = None;
+const COMMAND_TICK: Duration = Duration::from_secs(1);
+// Relay driven by the "relay" command, on GPIO6
+const RELAY: bool = false;
+// BOOT button (GPIO9) reopens the setup AP. Within the cooldown after
+// provisioning it takes a long press, then a confirm press.
+const REPROVISION_BUTTON: bool = true;
+const REPROVISION_COOLDOWN: Duration = Duration::from_secs(600);
+const REPROVISION_LONG_PRESS: Duration = Duration::from_secs(5);
+const REPROVISION_CONFIRM: Duration = Duration::from_secs(10);
+// LED on GPIO8, blinking while re-provisioning is armed
+const STATUS_LED: bool = true;
+// LED on GPIO7 showing the device state: fast blink in setup, slow blink while
+// connecting, solid while /healthz would answer ok, double blink on failing posts
+// or reads. GPIO8, the onboard LED, is taken by STATUS_LED.
+const STATE_LED: bool = false;
+// BOOT button (GPIO9) held this long at power-up erases the stored credentials
+const FACTORY_RESET_BUTTON: bool = true;
+const FACTORY_RESET_HOLD: Duration = Duration::from_secs(3);
+// BOOT button held at least SELF_TEST_HOLD at power-up, but let go before
+// FACTORY_RESET_HOLD, runs the bring-up self-test; the selftest feature runs it
+// on every boot. The verdict shows on the state LED for SELF_TEST_SHOW.
+const SELF_TEST_BUTTON: bool = true;
+const SELF_TEST_HOLD: Duration = Duration::from_secs(1);
+const SELF_TEST_SHOW: Duration = Duration::from_secs(10);
+// More boots than this within the window means a crash loop → safe mode
+const CRASH_LOOP_BOOTS: u32 = 5;
+const CRASH_LOOP_WINDOW_SECS: u64 = 120;
+// NVS namespace holding the credentials of the last successful setup
+const WIFI_NAMESPACE: &str = "wifi";
+const KEY_SSID: &str = "ssid";
+const KEY_PASS: &str = "pass";
+const KEY_EAP_IDENTITY: &str = "eap_identity";
+const KEY_EAP_USER: &str = "eap_user";
+const KEY_EAP_PASS: &str = "eap_pass";
+// Setup AP address and the range its DHCP server hands out; the `ap_net`
+// override replaces all four
+const AP_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+const AP_MASK: u8 = 24;
+const AP_DHCP_START: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 2);
+const AP_DHCP_END: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 20);
+const SETUP_AP_NET: ApNet = ApNet {
+    ip: AP_IP,
+    prefix: AP_MASK,
+    dhcp_start: AP_DHCP_START,
+    dhcp_end: AP_DHCP_END,
 };
-use dht_sensor::{dht11, DhtReading};
+const STOP_ATTEMPTS: u32 = 3;
+// Setup AP name and client limit; the cfg_ap_ssid and cfg_ap_clients NVS
+// keys override them
+const SETUP_SSID: &str = "ESP32_SETUP";
+const SETUP_MAX_CLIENTS: u16 = 4;
+// WPA2 passphrase of the setup AP (8-63 chars); None leaves it open, so anyone
+// in range can submit credentials during first-run setup
+const SETUP_PASS: Option<&str> = None;
+// Resolve every name to the setup AP and serve the page on any path, so phones
+// joining it pop up the setup page by themselves
+const CAPTIVE_PORTAL: bool = true;
+// How long `/scan` waits for the provisioning loop to finish a scan
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+// Keep the setup AP up while joining (AP+STA), so the setup page can link to
+// the device's new address. Off: the AP closes as soon as the station starts.
+const SETUP_KEEP_AP: bool = true;
+// How long a submission waits for the connection result before answering
+// "Accepted" and leaving the rest to `/status`
+const SETUP_RESULT_TIMEOUT: Duration = Duration::from_secs(30);
+// How long the AP lingers after connecting if the page never picks up the IP
+const SETUP_LINGER: Duration = Duration::from_secs(30);
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+// Hold the first sample back by a random part of the interval (at most this
+// long) after power-on, so boards started together don't post together; the
+// offset then holds for the whole run. None starts sampling right away.
+const STARTUP_JITTER: Option<Duration> = Some(Duration::from_secs(60));
+// Applied to Rust and IDF logs at boot; the cfg_log_level NVS key overrides it.
+// Debug also needs CONFIG_LOG_MAXIMUM_LEVEL_DEBUG, set in sdkconfig.defaults.
+const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
+// Hold back the per-cycle success lines (readings, post statuses) while
+// keeping warnings and errors; the cfg_quiet NVS key ("on"/"off") overrides it
+const LOG_QUIET: bool = false;
+// Log target of the per-cycle success lines that quiet mode holds back
+const CYCLE_LOG: &str = "cycle";
+// Post the per-sensor mean of the samples once per interval; None posts every sample
+const REPORT_INTERVAL: Option<Duration> = None;
+// Send a bare {"ping":true} this often between measurements, so the server sees
+// the device alive while SAMPLE_INTERVAL is long; None sends none. The
+// cfg_heartbeat NVS key overrides it, "0" switching it off.
+const HEARTBEAT_INTERVAL: Option<Duration> = None;
+// Reopen the setup page after this long with Wi-Fi up but no post getting
+// through, e.g. Some(Duration::from_secs(30 * 60)), so a moved server can be
+// set without a cable; None never does. The cfg_dead_man NVS key (minutes,
+// "0" for off) overrides it.
+const DEAD_MAN_TIMEOUT: Option<Duration> = None;
+// Per time-of-day intervals in seconds, e.g. "06:00-22:00=60,22:00-06:00=900".
+// Needs a set clock; SAMPLE_INTERVAL applies until then and outside the rules.
+const SCHEDULE: &str = "";
+const TZ_OFFSET_MINUTES: i64 = 0;
+// How long boot waits for the first NTP answer (pool.ntp.org) before sampling
+const NTP_SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest any single HTTP connect, write or read may block; a stalled server
+// then fails the send, which the sink retries with backoff
+const HTTP_TIMEOUT: Duration = Duration::from_secs(8);
+// Take one reading, send it, then deep sleep this long instead of staying awake.
+// Each wake is a fresh boot, so it only applies once credentials are stored.
+const DEEP_SLEEP: bool = false;
+const DEEP_SLEEP_INTERVAL: Duration = Duration::from_secs(300);
+// Sample faster while temperature moves: the interval halves on a change of
+// ADAPTIVE_FAST_DELTA °C or more between samples and grows by half while changes
+// stay within ADAPTIVE_STEADY_DELTA, between the two bounds. Replaces SCHEDULE.
+const ADAPTIVE: bool = false;
+const ADAPTIVE_MIN: Duration = Duration::from_secs(5);
+const ADAPTIVE_MAX: Duration = Duration::from_secs(60);
+const ADAPTIVE_FAST_DELTA: f32 = 0.5;
+const ADAPTIVE_STEADY_DELTA: f32 = 0.1;
+// Sent as "device_id" so one endpoint can tell several boards apart. None uses
+// "esp32-" and the last three MAC bytes; a name from the setup page overrides both.
+const DEVICE_ID: Option<&str> = None;
+// mDNS name the device answers to on the home network (without ".local"). None
+// uses "mk2-" and the device id, e.g. mk2-esp32-a1b2c3.local
+const MDNS_HOSTNAME: Option<&str> = None;
+// Sent as "Authorization: Bearer <token>" on every post; the setup page or the
+// cfg_token NVS key overrides it. None sends no auth header.
+const AUTH_TOKEN: Option<&str> = None;
+// With the gzip feature, HTTP bodies longer than this go out gzipped; only
+// enable it once the server is known to accept Content-Encoding: gzip
+#[cfg_attr(not(feature = "gzip"), allow(dead_code))]
+const GZIP_MIN_BYTES: usize = 512;
+// Body encoding of the HTTP sink; Encoding::Cbor needs the cbor feature, and
+// the cfg_encoding NVS key ("json" or "cbor") overrides it
+const ENCODING: Encoding = Encoding::Json;
+// Payload fields to send, e.g. &["temperature", "humidity", "ts"]; empty sends all
+const TELEMETRY_FIELDS: &[&str] = &[];
+// Payload shape with {{field}} placeholders, e.g.
+// r#"{"t":{{temperature}},"h":{{humidity}},"id":{{device_id}},"at":{{ts}}}"#;
+// None sends the usual JSON object
+const PAYLOAD_TEMPLATE: Option<&str> = None;
+// Sensor on GPIO4, "dht11" or "dht22"; the cfg_sensor NVS key overrides it
+const SENSOR_KIND: SensorKind = SensorKind::Dht11;
+// Unit the temperature is sent in; the cfg_unit NVS key ("C" or "F") overrides it
+const TEMP_UNIT: TempUnit = TempUnit::Celsius;
+// A failed DHT read is retried this often before the cycle counts as failed;
+// the sensor needs a couple of seconds to settle between reads
+const DHT_READ_ATTEMPTS: u32 = 3;
+const DHT_RETRY_SPACING: Duration = Duration::from_secs(2);
+// Report the mean of AVERAGE_WINDOW DHT reads spread over each sample interval
+// instead of a single read; failed reads are left out of the mean. Reads stay at
+// least DHT_RETRY_SPACING apart, so a short interval stretches to fit them.
+const AVERAGING: bool = false;
+const AVERAGE_WINDOW: u32 = 5;
+
+// DHT sensors as label => pin, e.g. `"indoor" => gpio4, "outdoor" => gpio10`. A
+// single unlabeled sensor ("") sends payloads as before; labeled ones are each
+// sent with their label as "sensor". Taking a pin used elsewhere does not compile.
+macro_rules! dht_pins {
+    ($p:ident) => {
+        take_pins!($p, "" => gpio4)
+    };
+}
+
+/// Moves each named pin out of the peripherals, as `(label, AnyIOPin)` pairs.
+macro_rules! take_pins {
+    ($p:ident, $($label:literal => $gpio:ident),+ $(,)?) => {
+        [$(($label, $p.pins.$gpio.downgrade())),+]
+    };
+}
+// Adds "read_duration_us" to each DHT reading
+const REPORT_READ_DURATION: bool = false;
+// Adds the device's own health to each payload: "read_ms" (the whole sensor
+// cycle), "post_ms" (the last flush), "success_pct" (flushes that emptied the
+// queue, over the last METRICS_WINDOW) and "wifi_reconnects" since boot
+const REPORT_METRICS: bool = false;
+const METRICS_WINDOW: usize = 20;
+// A healthy DHT11 read takes ~23 ms; slower ones are flagged in "quality"
+const SLOW_READ_US: u32 = 40_000;
+// Readings outside this range (°C), with humidity outside 0-100 %, or all zero
+// are counted in "implausible_readings" and dropped like a failed read; with
+// DROP_IMPLAUSIBLE off they are sent anyway, flagged in "quality"
+const PLAUSIBLE_TEMP: RangeInclusive<f32> = -40.0..=80.0;
+const DROP_IMPLAUSIBLE: bool = true;
+// Repair single-bit DHT11 checksum failures when the result is plausible; such
+// readings are flagged in "quality"
+const CHECKSUM_CORRECTION: bool = false;
+// Battery on GPIO3 through a divider, reported as "battery_pct"
+const VBAT_MONITOR: bool = false;
+const VBAT_DIVIDER: f32 = 2.0;
+const BATTERY_CURVE: &[(f32, u8)] = charge::LIPO_CURVE;
+// Analog sensor (soil moisture, light...) on the ADC1 pin below, read each
+// cycle and sent as "analog_mv" and "analog" = analog_mv * ANALOG_SCALE +
+// ANALOG_OFFSET. The cfg_an_scale and cfg_an_offset NVS keys override both.
+const ANALOG: bool = false;
+const ANALOG_SCALE: f32 = 1.0;
+const ANALOG_OFFSET: f32 = 0.0;
 
+// GPIO0-GPIO4 are ADC1 on the C3; GPIO3 is the battery's, and GPIO2 is a
+// strapping pin best left alone.
+macro_rules! analog_pin {
+    ($p:ident) => {
+        $p.pins.gpio1
+    };
+}
+// Flush after this many readings...
+const BATCH_MAX_COUNT: usize = 1;
+// ...or once the oldest buffered reading is this old
+const BATCH_MAX_AGE_SECS: u64 = 60;
+// Every reading goes to each of these; "sd" is only built with SD_LOG on
+const SINKS: &[&str] = &["http", "sd"];
+// Also append every payload to an SPI SD card as newline-delimited JSON, one
+// file per day, kept up while the network is down. Needs the sdcard feature;
+// the cfg_sd_log NVS key ("on"/"off") overrides it.
+const SD_LOG: bool = false;
+
+// SD card as (SPI host, SCK, MOSI, MISO, CS). GPIO20/21 are UART0's, so the
+// console has to stay on the USB port.
+#[cfg(feature = "sdcard")]
+macro_rules! sd_pins {
+    ($p:ident) => {
+        ($p.spi2, $p.pins.gpio0, $p.pins.gpio10, $p.pins.gpio20, $p.pins.gpio21)
+    };
+}
+// Samples buffered between the sensor and network threads, and what to drop
+// when it is full: "drop_oldest", "drop_newest" or "block" (up to the timeout)
+const SAMPLE_QUEUE: usize = 8;
+const BACKPRESSURE: &str = "drop_oldest";
+const BACKPRESSURE_BLOCK_MS: u64 = 2000;
+const SAMPLER_STACK: usize = 8 * 1024;
+// Samples kept in RAM for GET /history on the diagnostics server
+const HISTORY_LEN: usize = 50;
+// Payloads each sink holds while the server is unreachable; the oldest goes
+// first when full. A payload is one reading or one batch.
+const OUTBOX_CAPACITY: usize = 128;
+// A payload still getting 4xx after this many tries is dropped
+const POISON_MAX_REJECTS: u32 = 3;
+// After a failed flush a sink waits this long, doubling per failure up to the
+// cap, plus up to 25% jitter; a delivery resets it. There is no attempt limit
+// per payload: it stays queued, and MAX_POST_FAILURES bounds the whole run.
+const POST_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const POST_BACKOFF_CAP: Duration = Duration::from_secs(60);
+// Adds the negotiated Wi-Fi security ("auth_mode") to payloads and setup `/status`
+const REPORT_NET_STATS: bool = false;
+// Read critical NVS writes (stored config) back and retry once on a mismatch.
+// Doubles the flash reads, so off unless the flash is suspect.
+const NVS_VERIFY: bool = false;
+// Below this much free heap (bytes) the device saves its queue and reboots
+// before the allocator gives out; None disables the check
+const CRITICAL_HEAP: Option<u32> = Some(20 * 1024);
+// Task watchdog on the main loop: an iteration stuck this long, say on a POST
+// hanging off a half-open socket, resets the board. None leaves it off.
+const WATCHDOG_TIMEOUT: Option<Duration> = Some(Duration::from_secs(60));
+// A dropped link is rejoined up to WIFI_RECONNECT_ATTEMPTS times, the delay
+// between tries doubling over WIFI_RECONNECT_BACKOFF; after that the loop
+// carries on offline and tries another round WIFI_RECONNECT_PAUSE later
+const WIFI_RECONNECT_ATTEMPTS: u32 = 3;
+const WIFI_RECONNECT_BACKOFF: (Duration, Duration) =
+    (Duration::from_secs(2), Duration::from_secs(8));
+const WIFI_RECONNECT_PAUSE: Duration = Duration::from_secs(60);
+// Per try: time to associate, then to get an address
+const WIFI_ASSOC_TIMEOUT: Duration = Duration::from_secs(10);
+const WIFI_DHCP_TIMEOUT: Duration = Duration::from_secs(10);
+// A full join from a stopped radio: association (including the scan), then the
+// address. A wrong password fails the first, so it is reported quickly. The
+// cfg_dhcp_secs NVS key overrides the second.
+const JOIN_ASSOC_TIMEOUT: Duration = Duration::from_secs(10);
+const JOIN_DHCP_TIMEOUT: Duration = Duration::from_secs(20);
+// Joins tried with the stored credentials when only DHCP fails
+const STORED_JOIN_ATTEMPTS: u32 = 3;
+// Reboot once every network sink failed this many flushes in a row; a delivery resets it
+const MAX_POST_FAILURES: u32 = 10;
+// Before that, restart the Wi-Fi driver after this many with is_connected()
+// still true: a wedged IP stack reports the link up while nothing gets through.
+// None never does; the cfg_wifi_rbld NVS key ("0" for off) overrides it.
+const WIFI_REBUILD_AFTER: Option<u32> = Some(5);
+// Keep the HTTP sink's connection open between posts instead of reconnecting
+// (and redoing the TLS handshake) every time; it costs a socket held open
+const HTTP_KEEP_ALIVE: bool = false;
+// Write batched readings to the HTTP sink one at a time instead of joining
+// them into one body first
+const HTTP_STREAM_BATCHES: bool = true;
+// How telemetry goes out over HTTP; the cfg_http_method and cfg_headers NVS
+// keys override these. Extra headers are (name, value) pairs.
+const HTTP_METHOD: Method = Method::Post;
+const HTTP_HEADERS: &[(&str, &str)] = &[];
+// Shared HMAC key; when set, HTTP posts carry X-Mk2-Timestamp and X-Mk2-Signature
+const SIGNING_KEY: Option<&str> = None;
+
+// Resolved once at boot and sent with every payload
+static DEVICE: OnceLock<String> = OnceLock::new();
+// Reason of the last station disconnect, cleared before each join
+static STA_DISCONNECT: AtomicU16 = AtomicU16::new(0);
 
 #[derive(Clone)]
 struct SetupReq {
     ssid: String,
     pass: String,
+    // WPA2-Enterprise login; `pass` is unused when set
+    eap: Option<EapCreds>,
+    // Telemetry endpoint entered on the setup page, if any
+    url: Option<String>,
+    // Device name entered on the setup page, if any
+    device_id: Option<String>,
+    // Sample interval entered on the setup page, if any
+    interval_secs: Option<u64>,
+    // API token entered on the setup page, if any; never logged
+    auth_token: Option<String>,
+    // Fixed station address; None uses DHCP
+    static_ip: Option<StaticIp>,
+    // Further stored overrides by name, from a `POST /config`; already checked
+    settings: Vec<(String, String)>,
 }
 
+#[derive(Clone)]
+enum SetupState {
+    Waiting,
+    Connecting,
+    Connected(Ipv4Addr),
+    Failed(String),
+}
 
-fn url_decode(bytes: &[u8]) -> String {
-    let mut out = Vec::with_capacity(bytes.len());
-    let mut i = 0;
-    while i < bytes.len() {
-        match bytes[i] {
-            b'+' => out.push(b' '),
-            b'%' if i + 2 < bytes.len() => {
-                let hv = |c| match c {
-                    b'0'..=b'9' => Some(c - b'0'),
-                    b'a'..=b'f' => Some(c - b'a' + 10),
-                    b'A'..=b'F' => Some(c - b'A' + 10),
-                    _ => None,
-                };
-                if let (Some(h), Some(l)) = (hv(bytes[i + 1]), hv(bytes[i + 2])) {
-                    out.push((h << 4) | l);
-                    i += 2;
-                } else {
-                    out.push(bytes[i]);
-                }
+/// Provisioning progress, polled by the setup page through `/status`.
+struct SetupStatus {
+    state: SetupState,
+    // Set once `/status` handed the station IP to the page
+    ip_reported: bool,
+    config_warnings: u32,
+    // Negotiated security of the station link, once associated
+    auth_mode: Option<&'static str>,
+}
+
+impl SetupStatus {
+    /// Takes provisioning for one submission; false while another one holds
+    /// it, so only one valid submission at a time reaches the connect step. A
+    /// failed attempt frees it for a retry.
+    fn claim(&mut self) -> bool {
+        if !matches!(self.state, SetupState::Waiting | SetupState::Failed(_)) {
+            return false;
+        }
+        self.state = SetupState::Connecting;
+        true
+    }
+}
+
+type SharedSetup = Arc<Mutex<SetupStatus>>;
+
+/// One network listed by `/scan`.
+#[derive(Serialize)]
+struct ScannedAp {
+    ssid: String,
+    rssi: i8,
+    auth: &'static str,
+}
+
+/// A `/scan` request: the handler waits on the sender for the results.
+type ScanRequest = Sender<Vec<ScannedAp>>;
+
+const ALREADY_PROVISIONING: &str = "Provisioning already started by another client.";
+
+#[derive(Deserialize)]
+struct ConfigReq {
+    ssid: String,
+    #[serde(default)]
+    pass: String,
+    #[serde(default)]
+    eap_identity: Option<String>,
+    #[serde(default)]
+    eap_username: Option<String>,
+    #[serde(default)]
+    eap_password: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    device_id: Option<String>,
+    #[serde(default)]
+    interval_secs: Option<u64>,
+    #[serde(default)]
+    auth_token: Option<String>,
+    // "ip,gateway,netmask"
+    #[serde(default)]
+    static_ip: Option<String>,
+    // Any stored override by its config command name, as `GET /config` lists them
+    #[serde(default)]
+    settings: BTreeMap<String, String>,
+}
+
+impl ConfigReq {
+    /// Checks every field, returning the reason for each bad one.
+    fn validate(&self) -> Result<(), BTreeMap<String, String>> {
+        let mut errors = BTreeMap::new();
+        let mut check = |field: &str, result: Result<(), String>| {
+            if let Err(e) = result {
+                errors.insert(field.to_owned(), e);
             }
-            c => out.push(c),
+        };
+        check("ssid", config::validate_ssid(&self.ssid));
+        match self.eap() {
+            Some(eap) => check("eap", eap.check()),
+            None => check("pass", check_pass(&self.pass)),
+        }
+        check("url", check_url(self.url.as_deref()));
+        check("device_id", check_device_id(self.device_id.as_deref()));
+        check("auth_token", check_token(self.auth_token.as_deref()));
+        if let Some(static_ip) = &self.static_ip {
+            check("static_ip", StaticIp::parse(static_ip).map(drop));
+        }
+        if let Some(secs) = self.interval_secs {
+            check("interval_secs", parse_interval_secs(&secs.to_string()).map(drop));
+        }
+        for (name, value) in self.stored_settings() {
+            check(&format!("settings.{name}"), Config::check(name, value).map(drop));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn eap(&self) -> Option<EapCreds> {
+        EapCreds::from_fields(
+            self.eap_identity.clone(),
+            self.eap_username.clone(),
+            self.eap_password.clone(),
+        )
+    }
+
+    /// The settings to store, without a token exported as `REDACTED`.
+    fn stored_settings(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.settings
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .filter(|&(name, value)| !(name == "token" && value == config::REDACTED))
+    }
+}
+
+fn check_device_id(id: Option<&str>) -> Result<(), String> {
+    match id {
+        Some(id) if !config::valid_device_id(id) => {
+            Err(format!("device name '{id}' must be 1-32 letters, digits, '-' or '_'"))
         }
-        i += 1;
+        _ => Ok(()),
     }
-    String::from_utf8_lossy(&out).into_owned()
 }
 
+/// Empty for an open network, otherwise what WPA2 allows.
+fn check_pass(pass: &str) -> Result<(), String> {
+    if !pass.is_empty() && !(8..=64).contains(&pass.len()) {
+        return Err("pass must be empty (open network) or 8-64 bytes".into());
+    }
+    Ok(())
+}
 
-fn wait_for_ip(wifi: &EspWifi, timeout: Duration) -> Result<()> {
+fn check_token(token: Option<&str>) -> Result<(), String> {
+    match token {
+        Some(token) => Config::check("token", token).map(drop),
+        None => Ok(()),
+    }
+}
+
+/// Seconds between samples, held to the range stored config accepts.
+fn parse_interval_secs(v: &str) -> Result<u64, String> {
+    Config::check("interval", v)?;
+    v.parse().map_err(|_| format!("bad interval '{v}'"))
+}
+
+fn check_url(url: Option<&str>) -> Result<(), String> {
+    match url {
+        Some(url) if !config::valid_url(url) => {
+            Err(format!("'{url}' is not an http(s) or mqtt(s) URL"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Disconnect reasons that mean the AP checked the credentials and refused
+/// them, as opposed to never answering.
+fn auth_rejected(reason: u16) -> bool {
+    [
+        sys::wifi_err_reason_t_WIFI_REASON_AUTH_FAIL,
+        sys::wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT,
+        sys::wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT,
+    ]
+    .iter()
+    .any(|&rejected| rejected as u16 == reason)
+}
+
+/// Polls until the station is associated; `AppError::Association` once
+/// `timeout` passes without. A failed status read counts as not associated.
+fn wait_for_association(wifi: &EspWifi, timeout: Duration) -> Result<(), AppError> {
+    let started = Instant::now();
+    while !wifi.is_connected().unwrap_or(false) {
+        if started.elapsed() >= timeout {
+            return Err(AppError::Association);
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    Ok(())
+}
+
+fn wait_for_ip(wifi: &EspWifi, timeout: Duration) -> Result<Ipv4Addr, AppError> {
     let start = Instant::now();
+    // A fixed address is there from the start, so only wait for the link
+    if !dhcp_client_running(wifi.sta_netif()) {
+        while !(wifi.is_connected()? && wifi.sta_netif().is_up()?) {
+            if start.elapsed() > timeout {
+                return Err(AppError::Association);
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+        let info = wifi.sta_netif().get_ip_info()?;
+        log::info!("✅ Static IP: {:?}", info);
+        return Ok(info.ip);
+    }
     loop {
         let info = wifi.sta_netif().get_ip_info()?;
         if info.ip != Ipv4Addr::new(0, 0, 0, 0) {
-            println!("✅ Got IP: {:?}", info);
-            return Ok(());
+            log::info!("✅ Got IP: {:?}", info);
+            return Ok(info.ip);
         }
         if start.elapsed() > timeout {
-            return Err(anyhow!("Timeout DHCP"));
+            // Never associating and associating without a lease need different fixes
+            if !wifi.is_connected()? {
+                return Err(AppError::Association);
+            }
+            return Err(AppError::Dhcp(timeout));
         }
         thread::sleep(Duration::from_millis(250));
     }
 }
 
-fn start_ap(wifi: &mut EspWifi, ssid: &str) -> Result<()> {
-    wifi.set_configuration(&WifiConfiguration::AccessPoint(ApConfiguration {
-        ssid: ssid.try_into().unwrap(),
-        channel: 6,
-        auth_method: AuthMethod::None,
-        max_connections: 4,
+/// Rejoins the configured network if the link is down, up to
+/// `WIFI_RECONNECT_ATTEMPTS` tries with backoff. `feed` runs before each try,
+/// so a long round never trips the watchdog. The error is the last try's:
+/// `Association` or `Dhcp`, unless the driver itself failed.
+/// `Ok(true)` when the link had to be brought back.
+fn ensure_wifi_connected(wifi: &mut EspWifi, feed: &mut dyn FnMut()) -> Result<bool, AppError> {
+    let has_ip = |wifi: &EspWifi| {
+        wifi.sta_netif().get_ip_info().is_ok_and(|info| !info.ip.is_unspecified())
+    };
+    if wifi.is_connected().unwrap_or(false) && has_ip(wifi) {
+        return Ok(false);
+    }
+    let (base, cap) = WIFI_RECONNECT_BACKOFF;
+    let mut backoff = Backoff::new(base, cap);
+    let mut error = AppError::Association;
+    for attempt in 1..=WIFI_RECONNECT_ATTEMPTS {
+        feed();
+        if attempt > 1 {
+            thread::sleep(backoff.next_delay(backoff::random()));
+        }
+        log::info!("📡 Wi-Fi down, reconnecting ({attempt}/{WIFI_RECONNECT_ATTEMPTS})...");
+        if !wifi.is_connected().unwrap_or(false) {
+            if let Err(e) = wifi.connect() {
+                log::warn!("Wi-Fi connect failed: {:?}", e);
+            }
+            if let Err(e) = wait_for_association(wifi, WIFI_ASSOC_TIMEOUT) {
+                error = e;
+                continue;
+            }
+        }
+        match wait_for_ip(wifi, WIFI_DHCP_TIMEOUT) {
+            Ok(ip) => {
+                let rssi = wifi.driver_mut().get_ap_info().map(|ap| ap.signal_strength);
+                match rssi {
+                    Ok(rssi) => log::info!("✅ Wi-Fi back as {ip}, RSSI {rssi} dBm"),
+                    Err(_) => log::info!("✅ Wi-Fi back as {ip}"),
+                }
+                return Ok(true);
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Stops and restarts the driver, then joins again: for a link that reports
+/// itself up while every send fails. The netif and its addressing are kept.
+fn rebuild_wifi(wifi: &mut EspWifi, feed: &mut dyn FnMut()) -> Result<bool, AppError> {
+    stop_wifi(wifi)?;
+    wifi.start()?;
+    ensure_wifi_connected(wifi, feed)
+}
+
+fn dhcp_client_running(netif: &EspNetif) -> bool {
+    let mut status = sys::esp_netif_dhcp_status_t_ESP_NETIF_DHCP_INIT;
+    let read = esp!(unsafe { sys::esp_netif_dhcpc_get_status(netif.handle(), &mut status) });
+    read.is_err() || status != sys::esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STOPPED
+}
+
+/// Station interface with `static_ip` fixed on it, or a DHCP client without.
+fn sta_netif(static_ip: Option<&StaticIp>) -> Result<EspNetif> {
+    let Some(static_ip) = static_ip else {
+        return Ok(EspNetif::new_with_conf(&NetifConfiguration::wifi_default_client())?);
+    };
+    Ok(EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: Some(ipv4::Configuration::Client(ipv4::ClientConfiguration::Fixed(
+            ipv4::ClientSettings {
+                ip: static_ip.ip,
+                subnet: ipv4::Subnet {
+                    gateway: static_ip.gateway,
+                    mask: ipv4::Mask(static_ip.prefix),
+                },
+                // Home routers resolve for their clients
+                dns: Some(static_ip.gateway),
+                secondary_dns: None,
+            },
+        ))),
+        ..NetifConfiguration::wifi_default_client()
+    })?)
+}
+
+fn set_country(cc: &str) -> Result<()> {
+    if !config::valid_country(cc) {
+        return Err(anyhow!("Invalid country code '{cc}'"));
+    }
+    let c_cc = CString::new(cc)?;
+    // 802.11d off: stick to our domain instead of adopting the AP's
+    esp!(unsafe { sys::esp_wifi_set_country_code(c_cc.as_ptr(), false) })?;
+    Ok(())
+}
+
+/// Applies `WIFI_POWER_SAVE`; needs the driver started. A failure only costs
+/// current, so it is logged rather than failing the join.
+fn set_power_save() {
+    let name = match WIFI_POWER_SAVE {
+        sys::wifi_ps_type_t_WIFI_PS_NONE => "none",
+        sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM => "min-modem",
+        _ => "max-modem",
+    };
+    match esp!(unsafe { sys::esp_wifi_set_ps(WIFI_POWER_SAVE) }) {
+        Ok(()) => log::info!("Wi-Fi power save: {name}"),
+        Err(e) => log::warn!("Cannot set Wi-Fi power save to {name}: {:?}", e),
+    }
+}
+
+/// Sets the level for every log target, IDF components included. In quiet
+/// mode `CYCLE_LOG` lines need at least a warning to get through.
+fn apply_log_level(level: log::LevelFilter, quiet: bool) {
+    log::set_max_level(level);
+    // "*" resets every per-target level, so it goes first
+    let mut set = esp_idf_svc::log::set_target_level("*", level);
+    if quiet {
+        set = set.and_then(|()| {
+            esp_idf_svc::log::set_target_level(CYCLE_LOG, level.min(log::LevelFilter::Warn))
+        });
+    }
+    if let Err(e) = set {
+        log::warn!("Cannot set the log level: {:?}", e);
+    }
+}
+
+fn country_channels() -> Result<RangeInclusive<u8>> {
+    let mut country = sys::wifi_country_t::default();
+    esp!(unsafe { sys::esp_wifi_get_country(&mut country) })?;
+    Ok(country.schan..=country.schan + country.nchan.saturating_sub(1))
+}
+
+fn ap_netif(net: &ApNet) -> Result<EspNetif> {
+    net.check().map_err(|e| anyhow!("AP {e}"))?;
+    Ok(EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: Some(ipv4::Configuration::Router(ipv4::RouterConfiguration {
+            subnet: ipv4::Subnet { gateway: net.ip, mask: ipv4::Mask(net.prefix) },
+            dhcp_enabled: true,
+            dns: Some(net.ip),
+            secondary_dns: None,
+        })),
+        ..NetifConfiguration::wifi_default_router()
+    })?)
+}
+
+fn set_dhcp_pool(netif: &EspNetif, net: &ApNet) -> Result<()> {
+    let ip4 = |ip: Ipv4Addr| sys::ip4_addr_t { addr: u32::from_le_bytes(ip.octets()) };
+    let mut lease = sys::dhcps_lease_t {
+        enable: true,
+        start_ip: ip4(net.dhcp_start),
+        end_ip: ip4(net.dhcp_end),
+    };
+    // The lease range can only be changed while the DHCP server is stopped
+    unsafe {
+        sys::esp_netif_dhcps_stop(netif.handle());
+        esp!(sys::esp_netif_dhcps_option(
+            netif.handle(),
+            sys::esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+            sys::esp_netif_dhcp_option_id_t_ESP_NETIF_REQUESTED_IP_ADDRESS,
+            &mut lease as *mut _ as *mut _,
+            core::mem::size_of_val(&lease) as u32,
+        ))?;
+        esp!(sys::esp_netif_dhcps_start(netif.handle()))?;
+    }
+    Ok(())
+}
+
+/// The setup AP as `cfg` describes it, WPA2 when `SETUP_PASS` is set.
+fn ap_config(cfg: &Config) -> Result<ApConfiguration> {
+    let allowed = country_channels()?;
+    let channel = if allowed.contains(&cfg.ap_channel) {
+        cfg.ap_channel
+    } else {
+        let fallback = *allowed.start();
+        log::warn!("AP channel {} not allowed in this country, using {fallback}", cfg.ap_channel);
+        fallback
+    };
+    let (auth_method, password) = match SETUP_PASS {
+        Some(pass) if !(8..=63).contains(&pass.len()) => {
+            return Err(anyhow!("AP password must be 8-63 characters"));
+        }
+        Some(pass) => (AuthMethod::WPA2Personal, pass),
+        None => (AuthMethod::None, ""),
+    };
+    config::validate_ssid(&cfg.ap_ssid).map_err(|e| anyhow!("AP {e}"))?;
+    Ok(ApConfiguration {
+        ssid: cfg.ap_ssid.as_str().try_into().map_err(|_| anyhow!("AP SSID over 32 bytes"))?,
+        channel,
+        auth_method,
+        password: password.try_into().unwrap(),
+        max_connections: cfg.ap_max_clients,
         ..Default::default()
-    }))?;
+    })
+}
+
+fn start_ap(wifi: &mut EspWifi, cfg: &Config) -> Result<()> {
+    // An idle station next to the AP, since the driver only scans with one up
+    let idle = ClientConfiguration::default();
+    wifi.set_configuration(&WifiConfiguration::Mixed(idle, ap_config(cfg)?))?;
     wifi.start()?;
-    println!("📡 AP '{ssid}' started → http://192.168.71.1/");
+    set_dhcp_pool(wifi.ap_netif(), &cfg.ap_net)?;
+    let security = if SETUP_PASS.is_some() { "WPA2" } else { "open" };
+    // What the interface actually got, not what was asked for
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    log::info!("📡 AP '{}' ({security}) started → http://{ip}/", cfg.ap_ssid);
     Ok(())
 }
 
-fn connect_sta(wifi: &mut EspWifi, ssid: &str, pass: &str) -> Result<()> {
-    let _ = wifi.stop();
-    wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration {
-        ssid: ssid.try_into().map_err(|_| anyhow!("SSID invalide"))?,
-        password: pass.try_into().map_err(|_| anyhow!("MDP invalide"))?,
+fn stop_wifi(wifi: &mut EspWifi) -> Result<(), AppError> {
+    for attempt in 1..=STOP_ATTEMPTS {
+        if let Err(e) = wifi.stop() {
+            log::warn!("Wi-Fi stop failed (attempt {attempt}/{STOP_ATTEMPTS}): {:?}", e);
+        }
+        if !wifi.is_started()? {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    Err(AppError::WifiStuck(STOP_ATTEMPTS))
+}
+
+fn client_config(creds: &SetupReq) -> Result<ClientConfiguration, AppError> {
+    config::validate_ssid(&creds.ssid).map_err(AppError::Config)?;
+    // Enterprise logins go to the supplicant instead, see `eap::apply`
+    let pass = if creds.eap.is_some() { "" } else { creds.pass.as_str() };
+    let auth_method = match &creds.eap {
+        Some(_) => AuthMethod::WPA2Enterprise,
+        // Empty for an open network, which then needs AuthMethod::None: the
+        // default WPA2 threshold would never match it
+        None if pass.is_empty() => AuthMethod::None,
+        None => AuthMethod::WPA2Personal,
+    };
+    Ok(ClientConfiguration {
+        ssid: creds.ssid.as_str().try_into().map_err(|_| AppError::Config("SSID invalide".into()))?,
+        password: pass.try_into().map_err(|_| AppError::Config("MDP invalide".into()))?,
+        auth_method,
         ..Default::default()
-    }))?;
+    })
+}
+
+/// Credentials saved by the last successful setup; an empty password is an
+/// open network.
+fn load_credentials(nvs: &EspNvs<NvsDefault>) -> Option<SetupReq> {
+    let mut ssid_buf = [0u8; 33];
+    let mut pass_buf = [0u8; 65];
+    let ssid = nvs.get_str(KEY_SSID, &mut ssid_buf).ok().flatten()?;
+    let pass = nvs.get_str(KEY_PASS, &mut pass_buf).ok().flatten().unwrap_or("");
+    let stored = |key: &str| {
+        let mut buf = [0u8; 128];
+        nvs.get_str(key, &mut buf).ok().flatten().map(str::to_owned)
+    };
+    let eap = EapCreds::from_fields(
+        stored(KEY_EAP_IDENTITY),
+        stored(KEY_EAP_USER),
+        stored(KEY_EAP_PASS),
+    );
+    (!ssid.is_empty()).then(|| SetupReq {
+        ssid: ssid.to_owned(),
+        pass: pass.to_owned(),
+        eap,
+        url: None,
+        device_id: None,
+        interval_secs: None,
+        auth_token: None,
+        static_ip: None,
+        settings: Vec::new(),
+    })
+}
+
+fn save_credentials(nvs: &mut EspNvs<NvsDefault>, creds: &SetupReq) -> Result<()> {
+    persist::write_verified(nvs, KEY_SSID, &creds.ssid)?;
+    persist::write_verified(nvs, KEY_PASS, &creds.pass)?;
+    match &creds.eap {
+        Some(eap) => {
+            persist::write_verified(nvs, KEY_EAP_IDENTITY, &eap.identity)?;
+            persist::write_verified(nvs, KEY_EAP_USER, &eap.username)?;
+            persist::write_verified(nvs, KEY_EAP_PASS, &eap.password)?;
+        }
+        // A network that moved to PSK must not come back as enterprise
+        None => clear_eap(nvs)?,
+    }
+    log::info!("💾 Credentials for '{}' stored", creds.ssid);
+    Ok(())
+}
+
+/// How long the (active-low) button stays down from boot, up to `hold`; zero
+/// when it is up to begin with.
+fn held_at_boot(pin: &mut Gpio9, hold: Duration) -> Result<Duration> {
+    let mut button = PinDriver::input(pin)?;
+    button.set_pull(Pull::Up)?;
+    let since = Instant::now();
+    while button.is_low() {
+        if since.elapsed() >= hold {
+            return Ok(hold);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(since.elapsed())
+}
+
+/// Forgets the stored network so the next boot opens the setup AP.
+fn clear_credentials(nvs: &mut EspNvs<NvsDefault>) {
+    let cleared = nvs.remove(KEY_SSID).and_then(|_| nvs.remove(KEY_PASS));
+    if let Err(e) = cleared.and_then(|_| clear_eap(nvs)) {
+        log::warn!("Cannot clear stored credentials: {:?}", e);
+    }
+}
+
+fn clear_eap(nvs: &mut EspNvs<NvsDefault>) -> Result<(), sys::EspError> {
+    for key in [KEY_EAP_IDENTITY, KEY_EAP_USER, KEY_EAP_PASS] {
+        nvs.remove(key)?;
+    }
+    Ok(())
+}
+
+/// Joins the network, keeping the setup AP up alongside when `keep_ap`.
+fn connect_sta(
+    wifi: &mut EspWifi,
+    creds: &SetupReq,
+    cfg: &Config,
+    keep_ap: bool,
+) -> Result<Ipv4Addr, AppError> {
+    let config = |e: anyhow::Error| AppError::Config(format!("{e:#}"));
+    // Some IDF versions refuse set_configuration while the radio is up
+    stop_wifi(wifi)?;
+    // Swapped in every time, so a failed static attempt never sticks to a DHCP retry
+    wifi.swap_netif_sta(sta_netif(creds.static_ip.as_ref()).map_err(config)?)?;
+    let client = client_config(creds)?;
+    if keep_ap {
+        let ap = ap_config(cfg).map_err(config)?;
+        wifi.set_configuration(&WifiConfiguration::Mixed(client, ap))?;
+    } else {
+        wifi.set_configuration(&WifiConfiguration::Client(client))?;
+    }
+    eap::apply(creds.eap.as_ref())?;
     wifi.start()?;
+    // Both joins come through here, with or without the setup AP alongside
+    set_power_save();
+    if keep_ap {
+        set_dhcp_pool(wifi.ap_netif(), &cfg.ap_net).map_err(config)?;
+    }
+    STA_DISCONNECT.store(0, Ordering::Relaxed);
     wifi.connect()?;
-    wait_for_ip(wifi, Duration::from_secs(20))
+    if let Err(e) = wait_for_association(wifi, JOIN_ASSOC_TIMEOUT) {
+        let reason = STA_DISCONNECT.load(Ordering::Relaxed);
+        if auth_rejected(reason) {
+            log::warn!("'{}' rejected the credentials (reason {reason})", creds.ssid);
+            return Err(AppError::Auth(reason));
+        }
+        log::warn!("Not associated within {JOIN_ASSOC_TIMEOUT:?}: out of range or AP busy?");
+        return Err(e);
+    }
+    wait_for_ip(wifi, cfg.dhcp_timeout)
+}
+
+/// Drops the setup AP from AP+STA mode without leaving the network.
+fn close_setup_ap(wifi: &mut EspWifi, creds: &SetupReq, cfg: &Config) -> Result<()> {
+    let client = client_config(creds)?;
+    if let Err(e) = wifi.set_configuration(&WifiConfiguration::Client(client)) {
+        log::warn!("Cannot drop the AP while connected ({:?}), reconnecting", e);
+        connect_sta(wifi, creds, cfg, false)?;
+    }
+    log::info!("📡 Setup AP closed");
+    Ok(())
 }
 
+/// Visible networks, strongest first, each SSID once at its best signal.
+/// Hidden networks are left out.
+fn strongest_per_ssid(mut aps: Vec<AccessPointInfo>) -> Vec<ScannedAp> {
+    aps.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+    let mut seen = HashSet::new();
+    aps.into_iter()
+        .filter(|ap| !ap.ssid.is_empty() && seen.insert(ap.ssid.clone()))
+        .map(|ap| ScannedAp {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            auth: ap.auth_method.map_or("unknown", auth_mode_str),
+        })
+        .collect()
+}
 
-fn spawn_setup_server(tx: Sender<SetupReq>) -> Result<EspHttpServer<'static>> {
-    let mut server = EspHttpServer::new(&ServerConfig::default())?;
+fn scan_networks(wifi: &mut EspWifi) -> Vec<ScannedAp> {
+    match wifi.scan() {
+        Ok(aps) => strongest_per_ssid(aps),
+        Err(e) => {
+            log::warn!("Wi-Fi scan failed: {:?}", e);
+            Vec::new()
+        }
+    }
+}
 
-    server.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
+
+fn spawn_setup_server(
+    tx: Sender<SetupReq>,
+    scans: Sender<ScanRequest>,
+    nvs_part: EspDefaultNvsPartition,
+    notice: Option<String>,
+    status: SharedSetup,
+) -> Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&ServerConfig {
+        uri_match_wildcard: CAPTIVE_PORTAL,
+        ..Default::default()
+    })?;
+
+    let export_part = nvs_part.clone();
+    server.fn_handler("/reset", Method::Get, guarded(move |req| {
+        let mut nvs = EspNvs::new(nvs_part.clone(), WIFI_NAMESPACE, true)?;
+        clear_credentials(&mut nvs);
+        log::info!("🧹 Factory reset requested, rebooting");
         let mut r = req.into_ok_response()?;
-        r.write_all(br#"<!doctype html><html><body>
-<h3>ESP32 Setup</h3>
-<input id=ssid placeholder=SSID>
+        r.write_all(b"Stored Wi-Fi credentials erased. Rebooting...")?;
+        // Give the response time to flush before the restart
+        thread::spawn(|| {
+            thread::sleep(Duration::from_millis(500));
+            esp_idf_svc::hal::reset::restart();
+        });
+        Ok(())
+    }))?;
+
+    // The radio belongs to the provisioning loop, which runs the scan for us
+    server.fn_handler("/scan", Method::Get, guarded(move |req| {
+        let (reply, results) = channel();
+        // A failed or slow scan lists nothing rather than erroring
+        let aps = match scans.send(reply) {
+            Ok(()) => results.recv_timeout(SCAN_TIMEOUT).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(serde_json::to_string(&aps)?.as_bytes())?;
+        Ok(())
+    }))?;
+
+    let page_notice = notice.clone();
+    server.fn_handler("/", Method::Get, guarded(move |req| {
+        setup_page(req, page_notice.as_deref())
+    }))?;
+
+    let tx2 = tx.clone();
+    let setup_status = status.clone();
+    server.fn_handler("/setup", Method::Post, guarded(move |mut req| {
+        // A client that keeps streaming is cut off at the cap rather than
+        // read until the heap runs out
+        let announced = req
+            .header("Content-Length")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        let body = if announced > SETUP_MAX_BODY {
+            None
+        } else {
+            read_body_capped(&mut req, SETUP_MAX_BODY)?
+        };
+        let Some(body) = body else {
+            log::warn!("Setup body over {SETUP_MAX_BODY} bytes refused");
+            let msg = format!("Setup body over {SETUP_MAX_BODY} bytes");
+            return reply_error(req, 413, "Payload Too Large", &msg);
+        };
+
+        let mut form = parse_form(&String::from_utf8_lossy(&body));
+        let ssid = match form.remove("ssid") {
+            Some(ssid) => config::validate_ssid(&ssid).map(|()| ssid),
+            None => Err("Missing ssid field".to_owned()),
+        };
+        let pass = form.remove("pass").unwrap_or_default();
+        let eap = EapCreds::from_fields(
+            form.remove("eap_identity"),
+            form.remove("eap_user"),
+            form.remove("eap_pass"),
+        );
+        let url = form.remove("url").filter(|url| !url.is_empty());
+        let device_id = form.remove("device_id").filter(|id| !id.is_empty());
+        let interval = form.remove("interval_secs").filter(|v| !v.is_empty());
+        let auth_token = form.remove("token").filter(|token| !token.is_empty());
+        let static_ip = form.remove("ip").filter(|ip| !ip.is_empty()).map(|ip| {
+            let gateway = form.remove("gateway").unwrap_or_default();
+            let netmask = form.remove("netmask").unwrap_or_default();
+            StaticIp::parse(&format!("{ip},{gateway},{netmask}"))
+        });
+        let checked = ssid
+            .and_then(|ssid| match &eap {
+                Some(eap) => eap.check().map(|()| ssid),
+                None => check_pass(&pass).map(|()| ssid),
+            })
+            .and_then(|ssid| check_url(url.as_deref()).map(|()| ssid))
+            .and_then(|ssid| check_device_id(device_id.as_deref()).map(|()| ssid))
+            .and_then(|ssid| check_token(auth_token.as_deref()).map(|()| ssid))
+            .and_then(|ssid| {
+                let secs = interval.as_deref().map(parse_interval_secs).transpose()?;
+                Ok((ssid, secs, static_ip.transpose()?))
+            });
+        let (ssid, interval_secs, static_ip) = match checked {
+            Ok(checked) => checked,
+            Err(msg) => return reply_error(req, 400, "Bad Request", &msg),
+        };
+
+        log::info!("📡 Received setup: ssid='{ssid}', pass_len={}", pass.len());
+        if !setup_status.lock().unwrap().claim() {
+            return reply_error(req, 409, "Conflict", ALREADY_PROVISIONING);
+        }
+        let _ = tx2.send(SetupReq {
+            ssid,
+            pass,
+            eap,
+            url,
+            device_id,
+            interval_secs,
+            auth_token,
+            static_ip,
+            settings: Vec::new(),
+        });
+        respond_outcome(req, &setup_status)
+    }))?;
+
+    let poll_status = status.clone();
+    server.fn_handler("/status", Method::Get, guarded(move |req| {
+        let mode = if SETUP_KEEP_AP { "ap+sta" } else { "ap" };
+        let mut status = poll_status.lock().unwrap();
+        let mut body = match &status.state {
+            SetupState::Waiting => serde_json::json!({ "state": "waiting", "mode": mode }),
+            SetupState::Connecting => serde_json::json!({ "state": "connecting", "mode": mode }),
+            SetupState::Connected(ip) => {
+                status.ip_reported = true;
+                serde_json::json!({ "state": "connected", "mode": mode, "ip": ip.to_string() })
+            }
+            SetupState::Failed(e) => serde_json::json!({ "state": "failed", "mode": mode, "error": e }),
+        };
+        body["config_warnings"] = status.config_warnings.into();
+        if let Some(auth) = status.auth_mode.filter(|_| REPORT_NET_STATS) {
+            body["auth_mode"] = auth.into();
+        }
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(body.to_string().as_bytes())?;
+        Ok(())
+    }))?;
+
+    server.fn_handler("/config", Method::Post, guarded(move |mut req| {
+        // Refuse oversized bodies up front when the client announces them
+        let announced = req
+            .header("Content-Length")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        let body = if announced > CONFIG_MAX_BODY {
+            None
+        } else {
+            read_body_capped(&mut req, CONFIG_MAX_BODY)?
+        };
+        let Some(body) = body else {
+            let msg = format!("Config body over {CONFIG_MAX_BODY} bytes");
+            return reply_error(req, 413, "Payload Too Large", &msg);
+        };
+
+        // Nothing is applied unless every field passes
+        let parsed = serde_json::from_slice::<ConfigReq>(&body)
+            .map_err(|e| BTreeMap::from([("body".to_owned(), format!("Invalid JSON: {e}"))]))
+            .and_then(|cfg| cfg.validate().map(|()| cfg));
+        let cfg = match parsed {
+            Ok(cfg) => cfg,
+            Err(errors) => {
+                let body = serde_json::json!({ "errors": errors });
+                let mut r = req.into_response(
+                    400,
+                    Some("Bad Request"),
+                    &[("Content-Type", "application/json")],
+                )?;
+                r.write_all(body.to_string().as_bytes())?;
+                return Ok(());
+            }
+        };
+
+        log::info!("📡 Received config: ssid='{}', pass_len={}", cfg.ssid, cfg.pass.len());
+        if !status.lock().unwrap().claim() {
+            return reply_error(req, 409, "Conflict", ALREADY_PROVISIONING);
+        }
+        let _ = tx.send(SetupReq {
+            eap: cfg.eap(),
+            ssid: cfg.ssid,
+            pass: cfg.pass,
+            url: cfg.url,
+            device_id: cfg.device_id,
+            interval_secs: cfg.interval_secs,
+            auth_token: cfg.auth_token,
+            static_ip: cfg.static_ip.as_deref().and_then(|v| StaticIp::parse(v).ok()),
+            settings: cfg
+                .stored_settings()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        });
+        respond_outcome(req, &status)
+    }))?;
+
+    // Exported in the shape `POST /config` takes; the password is never shown
+    server.fn_handler("/config", Method::Get, guarded(move |req| {
+        let wifi_nvs = EspNvs::new(export_part.clone(), WIFI_NAMESPACE, true)?;
+        let nvs = EspNvs::new(export_part.clone(), "mk2", true)?;
+        let ssid = load_credentials(&wifi_nvs).map(|creds| creds.ssid).unwrap_or_default();
+        let settings: serde_json::Map<_, _> = Config::load(&nvs)
+            .settings()
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.into()))
+            .collect();
+        let body = serde_json::json!({ "ssid": ssid, "settings": settings });
+        let mut r = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        r.write_all(body.to_string().as_bytes())?;
+        Ok(())
+    }))?;
+
+    if CAPTIVE_PORTAL {
+        // Registered last so the routes above match first. Phones probe some
+        // fixed URL after joining and show whatever comes back instead.
+        server.fn_handler("/*", Method::Get, guarded(move |req| {
+            setup_page(req, notice.as_deref())
+        }))?;
+    }
+
+    Ok(server)
+}
+
+/// The setup form, with `notice` shown above it when given.
+fn setup_page(req: Request<&mut EspHttpConnection>, notice: Option<&str>) -> Result<()> {
+    let mut r = req.into_ok_response()?;
+    r.write_all(b"<!doctype html><html><body>\n<h3>ESP32 Setup</h3>\n")?;
+    if let Some(notice) = notice {
+        r.write_all(format!("<p style=color:#c00>{notice}</p>\n").as_bytes())?;
+    }
+    r.write_all(br#"<ul id=nets></ul>
+<input id=ssid placeholder=SSID oninput="pass.disabled=false;pass.placeholder='Password'">
 <input id=pass placeholder=Password type=password>
-<button onclick="send()">Connect</button>
+"#)?;
+    if cfg!(feature = "enterprise") {
+        r.write_all(br#"<input id=eapu placeholder="Enterprise username (WPA2-Enterprise only)">
+<input id=eapi placeholder="Anonymous identity (optional)">
+<input id=eapp placeholder="Enterprise password" type=password>
+"#)?;
+    }
+    r.write_all(br#"<input id=url placeholder="Server URL (optional)">
+<input id=dev placeholder="Device name (optional)">
+<input id=every type=number min=2 max=3600 placeholder="Sample every N s (optional)">
+<input id=tok type=password placeholder="API token (optional)">
+<input id=ip placeholder="Static IP (blank for DHCP)">
+<input id=gw placeholder="Gateway">
+<input id=mask placeholder="Netmask, e.g. 255.255.255.0">
+<button id=go onclick="send()">Connect</button>
 <p id=s></p>
 <script>
 async function send(){
  const ssid=document.getElementById('ssid').value.trim();
  const pass=document.getElementById('pass').value.trim();
  if(!ssid){s.textContent='Missing SSID';return;}
- const body=`ssid=${encodeURIComponent(ssid)}&pass=${encodeURIComponent(pass)}`;
- const r=await fetch('/setup',{method:'POST',headers:{'Content-Type':'application/x-www-form-urlencoded'},body});
- s.textContent=await r.text();
+ const user=window.eapu?eapu.value.trim():'';
+ if(!user&&pass&&pass.length<8){s.textContent='Password must be 8+ characters, or empty for an open network';return;}
+ const url=document.getElementById('url').value.trim();
+ let body=`ssid=${encodeURIComponent(ssid)}&pass=${encodeURIComponent(pass)}`;
+ if(user){
+  body+=`&eap_user=${encodeURIComponent(user)}&eap_identity=${encodeURIComponent(eapi.value.trim())}`;
+  body+=`&eap_pass=${encodeURIComponent(eapp.value)}`;
+ }
+ if(url)body+=`&url=${encodeURIComponent(url)}`;
+ const dev=document.getElementById('dev').value.trim();
+ if(dev)body+=`&device_id=${encodeURIComponent(dev)}`;
+ const every=document.getElementById('every').value.trim();
+ if(every)body+=`&interval_secs=${encodeURIComponent(every)}`;
+ const tok=document.getElementById('tok').value.trim();
+ if(tok)body+=`&token=${encodeURIComponent(tok)}`;
+ const ip=document.getElementById('ip').value.trim();
+ if(ip){
+  const gw=document.getElementById('gw').value.trim(),mask=document.getElementById('mask').value.trim();
+  body+=`&ip=${encodeURIComponent(ip)}&gateway=${encodeURIComponent(gw)}&netmask=${encodeURIComponent(mask)}`;
+ }
+ s.textContent='Connecting...';
+ // A second tap would only get 409 while this attempt is in flight
+ go.disabled=true;
+ let r;
+ try{r=await fetch('/setup',{method:'POST',headers:{'Content-Type':'application/x-www-form-urlencoded'},body});}
+ catch(e){poll();return;}
+ if(r.ok){s.textContent=await r.text();poll();return;}
+ s.textContent=(await r.json().catch(()=>({error:r.statusText}))).error;
+ go.disabled=false;
+}
+async function poll(){
+ let st;
+ try{st=await (await fetch('/status')).json();}catch(e){
+  s.textContent='Setup network closed. Rejoin your home network to reach the device.';return;}
+ if(st.state=='connected'){
+  s.innerHTML=`Connected as ${st.ip}. Rejoin your home network, then <a href="http://${st.ip}/">open the device dashboard</a>.`;
+ }else if(st.state=='failed'){s.textContent='Connection failed: '+st.error;go.disabled=false;}
+ else{setTimeout(poll,1000);}
 }
+async function scan(){
+ let aps=[];
+ try{aps=await (await fetch('/scan')).json();}catch(e){}
+ for(const ap of aps){
+  const li=document.createElement('li');
+  const open=ap.auth=='open';
+  li.textContent=`${open?'\u{1F513} ':''}${ap.ssid} (${ap.rssi} dBm, ${ap.auth})`;
+  li.onclick=()=>{
+   ssid.value=ap.ssid;pass.disabled=open;
+   pass.placeholder=open?'Open network, no password':'Password';
+   if(open){pass.value='';}
+   else if(ap.auth=='wpa2-enterprise'&&window.eapu){eapu.focus();}
+   else{pass.focus();}
+  };
+  nets.appendChild(li);
+ }
+}
+scan();
 </script></body></html>"#)?;
-        Ok(())
-    })?;
+    Ok(())
+}
 
-    let tx2 = tx.clone();
-    server.fn_handler("/setup", Method::Post, move |mut req| -> anyhow::Result<()> {
-        let mut body = Vec::new();
-        let mut buf = [0u8; 512];
-        loop {
-            let n = req.read(&mut buf)?;
-            if n == 0 {
-                break;
-            }
-            body.extend_from_slice(&buf[..n]);
+/// Answers a submission once its connection attempt is decided. AP-only mode
+/// can't wait for that: the AP goes down while the device joins.
+fn respond_outcome(req: Request<&mut EspHttpConnection>, status: &SharedSetup) -> Result<()> {
+    let outcome = if SETUP_KEEP_AP { wait_for_outcome(status) } else { None };
+    let msg = match outcome {
+        Some(Ok(ip)) => format!("Connected as {ip}."),
+        Some(Err(e)) => {
+            let msg = format!("Connection failed: {e}. Check the credentials and retry.");
+            return reply_error(req, 502, "Bad Gateway", &msg);
         }
+        None => accepted_message().to_owned(),
+    };
+    let mut r = req.into_ok_response()?;
+    r.write_all(msg.as_bytes())?;
+    Ok(())
+}
 
-        let body_str = String::from_utf8_lossy(&body);
-        let mut ssid = String::new();
-        let mut pass = String::new();
+/// Answers `{"error": msg}`, the way the JSON endpoints report a failure.
+fn reply_error(
+    req: Request<&mut EspHttpConnection>,
+    code: u16,
+    reason: &str,
+    msg: &str,
+) -> Result<()> {
+    let body = serde_json::json!({ "error": msg });
+    let mut r = req.into_response(code, Some(reason), &[("Content-Type", "application/json")])?;
+    r.write_all(body.to_string().as_bytes())?;
+    Ok(())
+}
 
-        for pair in body_str.split('&') {
-            let mut kv = pair.splitn(2, '=');
-            let key = kv.next().unwrap_or("");
-            let val = kv.next().unwrap_or("");
-            let val_decoded = url_decode(val.as_bytes());
-            match key {
-                "ssid" => ssid = val_decoded,
-                "pass" => pass = val_decoded,
-                _ => {}
-            }
+/// Wraps an HTTP handler so that an error it returns before answering becomes
+/// a 500 with a fixed message. The server's own error page would show the
+/// error's debug output, which can carry NVS or IDF details; those go to the
+/// log instead.
+fn guarded<F>(
+    handler: F,
+) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<()> + Send
+where
+    F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<()> + Send,
+{
+    move |req| {
+        let uri = req.uri().to_owned();
+        let conn = req.release();
+        let Err(e) = handler(Request::wrap(&mut *conn)) else {
+            return Ok(());
+        };
+        log::error!("HTTP request {uri} failed: {e:#}");
+        if conn.is_response_initiated() {
+            // Headers are out; all that is left is to cut the response short
+            return Ok(());
+        }
+        let msg = "Internal error, see the device log";
+        reply_error(Request::wrap(conn), 500, "Internal Server Error", msg)
+    }
+}
+
+/// The result of the attempt in progress; None if it is still running after
+/// `SETUP_RESULT_TIMEOUT`.
+fn wait_for_outcome(status: &SharedSetup) -> Option<Result<Ipv4Addr, String>> {
+    let started = Instant::now();
+    while started.elapsed() < SETUP_RESULT_TIMEOUT {
+        match &status.lock().unwrap().state {
+            SetupState::Connected(ip) => return Some(Ok(*ip)),
+            SetupState::Failed(e) => return Some(Err(e.clone())),
+            SetupState::Waiting | SetupState::Connecting => {}
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    None
+}
+
+fn accepted_message() -> &'static str {
+    if SETUP_KEEP_AP {
+        "Accepted. Trying to connect..."
+    } else {
+        "Accepted. This setup network closes while the device joins yours: \
+         reconnect this phone to your home network to reach it."
+    }
+}
+
+/// Reads the whole body, or returns `None` as soon as it grows past `max`.
+fn read_body_capped(req: &mut Request<&mut EspHttpConnection>, max: usize) -> Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = req.read(&mut buf)?;
+        if n == 0 {
+            return Ok(Some(body));
+        }
+        if body.len() + n > max {
+            return Ok(None);
         }
+        body.extend_from_slice(&buf[..n]);
+    }
+}
 
-        println!("📡 Received setup: ssid='{ssid}', pass_len={}", pass.len());
-        let _ = tx2.send(SetupReq { ssid, pass });
+fn local_minute(stamp: &Stamp) -> Option<u16> {
+    let local_secs = (stamp.unix_ms? / 1000) as i64 + TZ_OFFSET_MINUTES * 60;
+    Some(local_secs.div_euclid(60).rem_euclid(24 * 60) as u16)
+}
 
-        let mut r = req.into_ok_response()?;
-        r.write_all(b"Accepted. Trying to connect...")?;
-        Ok(())
-    })?;
+/// Per-payload values that don't come from the measurement itself.
+struct Envelope {
+    stamp: Stamp,
+    battery_pct: Option<u8>,
+    dropped: u32,
+    // Readings averaged into this one, when aggregating
+    samples: Option<u32>,
+    auth_mode: Option<&'static str>,
+    unit: TempUnit,
+    boot_count: u32,
+    reset_reason: &'static str,
+    read_errors: ReadErrors,
+    analog: Option<AnalogReading>,
+    read_ms: u32,
+    metrics: metrics::Report,
+}
 
-    Ok(server)
+fn measurement_json(m: &Measurement, env: &Envelope, fields: &FieldFilter) -> Result<String> {
+    let no_sensor = m.quality & QUALITY_NO_SENSOR != 0;
+    let stamp = &env.stamp;
+    fields.to_json(&Telemetry {
+        ping: true,
+        device_id: DEVICE.get().map(String::as_str),
+        temperature: (!no_sensor).then(|| env.unit.convert(m.temperature)),
+        humidity: m.humidity.filter(|_| !no_sensor),
+        sensor: m.tag.as_deref(),
+        ts: stamp.unix_ms,
+        uptime_ms: stamp.unix_ms.is_none().then_some(stamp.uptime_ms),
+        time_source: stamp.source.as_str(),
+        read_duration_us: m.read_duration_us.filter(|_| REPORT_READ_DURATION),
+        quality: m.quality,
+        dropped: env.dropped,
+        battery_pct: env.battery_pct,
+        samples: env.samples,
+        sensor_error: no_sensor,
+        auth_mode: env.auth_mode.filter(|_| REPORT_NET_STATS),
+        unit: env.unit.symbol(),
+        boot_count: env.boot_count,
+        reset_reason: env.reset_reason,
+        dht_timeouts: env.read_errors.timeouts,
+        dht_checksum_errors: env.read_errors.checksum_mismatches,
+        implausible_readings: env.read_errors.implausible,
+        analog_mv: env.analog.map(|a| a.millivolts),
+        analog: env.analog.map(|a| a.value),
+        read_ms: Some(env.read_ms).filter(|_| REPORT_METRICS),
+        post_ms: env.metrics.post_ms.filter(|_| REPORT_METRICS),
+        success_pct: env.metrics.success_pct.filter(|_| REPORT_METRICS),
+        wifi_reconnects: Some(env.metrics.wifi_reconnects).filter(|_| REPORT_METRICS),
+    })
+}
+
+fn auth_mode_str(auth: AuthMethod) -> &'static str {
+    match auth {
+        AuthMethod::None => "open",
+        AuthMethod::WEP => "wep",
+        AuthMethod::WPA => "wpa",
+        AuthMethod::WPA2Personal => "wpa2",
+        AuthMethod::WPAWPA2Personal => "wpa/wpa2",
+        AuthMethod::WPA2Enterprise => "wpa2-enterprise",
+        AuthMethod::WPA3Personal => "wpa3",
+        AuthMethod::WPA2WPA3Personal => "wpa2/wpa3",
+        AuthMethod::WAPIPersonal => "wapi",
+    }
+}
+
+/// "esp32-" and the last three bytes of the factory MAC, e.g. `esp32-a1b2c3`.
+fn mac_device_id() -> String {
+    let mut mac = [0u8; 6];
+    if let Err(e) = esp!(unsafe { sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) }) {
+        log::warn!("Cannot read the factory MAC: {:?}", e);
+    }
+    format!("esp32-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
+/// `MDNS_HOSTNAME`, else "mk2-" and `device_id` held to the letters, digits
+/// and dashes a hostname allows.
+fn mdns_hostname(device_id: &str) -> String {
+    if let Some(hostname) = MDNS_HOSTNAME {
+        return hostname.to_owned();
+    }
+    let name: String = device_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    format!("mk2-{name}")
+}
+
+/// Answers to `<hostname>.local` and advertises the diagnostics server as
+/// `_http._tcp`. Advertising stops when the returned handle is dropped.
+fn advertise_mdns(hostname: &str, device_id: &str) -> Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(device_id)?;
+    mdns.add_service(None, "_http", "_tcp", 80, &[("path", "/status")])?;
+    log::info!("🔎 Reachable as http://{hostname}.local/");
+    Ok(mdns)
+}
+
+/// Security the station actually negotiated with its AP; None before it is
+/// associated, or when the driver reports a mode we don't know.
+fn negotiated_auth(wifi: &mut EspWifi) -> Option<&'static str> {
+    let info = wifi.driver_mut().get_ap_info().ok()?;
+    info.auth_method.map(auth_mode_str)
+}
+
+/// Queues a liveness ping on every network sink with nothing pending. A sink still
+/// working through a backlog skips it: a late heartbeat tells nothing.
+fn push_heartbeat(sinks: &mut [Sink]) {
+    let payload = serde_json::json!({
+        "ping": true,
+        "device_id": DEVICE.get(),
+    })
+    .to_string();
+    for sink in sinks.iter_mut().filter(|sink| sink.is_empty() && !sink.is_local()) {
+        sink.push(payload.clone());
+    }
+}
+
+/// Queues the batch on every sink as one payload; `samples` are the history
+/// ids it carries, empty when nothing will look at their outcome.
+fn push_batch(batch: &mut Batch<String>, sinks: &mut [Sink], samples: Range<u32>) {
+    let mut readings = batch.take();
+    // A lone reading keeps the original single-object payload
+    let payload = if readings.len() == 1 {
+        Payload::One(readings.remove(0))
+    } else {
+        Payload::Array(readings)
+    };
+    for sink in sinks.iter_mut() {
+        sink.push_samples(payload.clone(), samples.clone());
+    }
+}
+
+/// Reboots on purpose, keeping queued payloads in NVS for the next boot.
+fn planned_restart(nvs: &mut EspNvs<NvsDefault>, sinks: &mut [Sink], batch: &mut Batch<String>) -> ! {
+    if !batch.is_empty() {
+        push_batch(batch, sinks, 0..0);
+    }
+    persist::buffer_persist(nvs, sinks);
+    esp_idf_svc::hal::reset::restart()
+}
+
+/// Saves the queue, stops the radio and deep sleeps for `DEEP_SLEEP_INTERVAL`.
+/// RAM does not survive, so the device wakes up through a normal boot.
+fn sleep_until_next_reading(
+    nvs: &mut EspNvs<NvsDefault>,
+    sinks: &mut [Sink],
+    batch: &mut Batch<String>,
+    wifi: &mut EspWifi,
+) -> ! {
+    if !batch.is_empty() {
+        push_batch(batch, sinks, 0..0);
+    }
+    persist::buffer_persist(nvs, sinks);
+    // Powering down with the radio still transmitting can brown out the board
+    if let Err(e) = stop_wifi(wifi) {
+        log::warn!("Sleeping with Wi-Fi still up: {:?}", e);
+    }
+    log::info!("😴 Deep sleep for {:?}", DEEP_SLEEP_INTERVAL);
+    unsafe { sys::esp_deep_sleep(DEEP_SLEEP_INTERVAL.as_micros() as u64) }
+}
+
+/// Installs the signed image at `url` if it is newer, and reboots into it.
+/// The outcome is kept for `/ota/status`.
+fn check_and_apply_ota(
+    url: &str,
+    health: &SharedHealth,
+    nvs: &mut EspNvs<NvsDefault>,
+    sinks: &mut [Sink],
+    batch: &mut Batch<String>,
+) {
+    let state = ota::apply_signed_ota(url);
+    log::info!("⬇️ OTA: {} {}", state.as_str(), state.detail().unwrap_or(""));
+    let applied = matches!(state, ota::OtaState::Applied);
+    health.lock().unwrap().ota = state;
+    if applied {
+        planned_restart(nvs, sinks, batch);
+    }
+}
+
+/// Tells the server why the device goes away, then reboots through
+/// `planned_restart` to get a clean heap back.
+fn low_heap_restart(
+    free: u32,
+    nvs: &mut EspNvs<NvsDefault>,
+    sinks: &mut [Sink],
+    batch: &mut Batch<String>,
+) -> ! {
+    if !batch.is_empty() {
+        push_batch(batch, sinks, 0..0);
+    }
+    let event = serde_json::json!({ "event": "shutdown", "reason": "low_heap", "free_heap": free });
+    for sink in sinks.iter_mut() {
+        sink.push(event.to_string());
+        sink.flush();
+    }
+    planned_restart(nvs, sinks, batch)
+}
+
+/// Sensor readings from one sampling cycle.
+struct Sample {
+    at: Instant,
+    stamp: Stamp,
+    measurements: Vec<Measurement>,
+    battery_pct: Option<u8>,
+    // Totals over all sensors since boot
+    read_errors: ReadErrors,
+    analog: Option<AnalogReading>,
+    // Time the sensors took this cycle
+    read_ms: u32,
+}
+
+/// How the sampler picks the time until its next read.
+enum Pacing {
+    Scheduled { schedule: Schedule, base: Duration },
+    Adaptive(Adaptive),
+}
+
+/// Passes a plausible reading through. Otherwise it is counted, then flagged,
+/// or with DROP_IMPLAUSIBLE reported like a failed read: a placeholder for a
+/// tagged reading, nothing for an untagged one.
+fn screen_reading(mut m: Measurement, implausible: &mut u32) -> Option<Measurement> {
+    if plausible(&m, &PLAUSIBLE_TEMP) {
+        return Some(m);
+    }
+    *implausible += 1;
+    log::warn!(
+        "Implausible reading {:.1} °C / {:?} % ({implausible} so far)",
+        m.temperature,
+        m.humidity
+    );
+    if !DROP_IMPLAUSIBLE {
+        m.quality |= QUALITY_IMPLAUSIBLE;
+        return Some(m);
+    }
+    m.tag.map(|tag| Measurement { tag: Some(tag), quality: QUALITY_NO_SENSOR, ..Default::default() })
+}
+
+/// Random offset up to `interval` (capped at `STARTUP_JITTER`) for the first
+/// sample. A deep sleep wake keeps the phase the first boot picked, so only
+/// other boots draw one.
+fn startup_phase(interval: Duration) -> Duration {
+    let Some(cap) = STARTUP_JITTER else {
+        return Duration::ZERO;
+    };
+    if WakeupReason::get() == WakeupReason::Timer {
+        return Duration::ZERO;
+    }
+    let span_ms = interval.min(cap).as_millis() as u64;
+    let phase = Duration::from_millis(backoff::random() as u64 % (span_ms + 1));
+    log::info!("🎲 First sample in {} ms, keeping that offset", phase.as_millis());
+    phase
+}
+
+/// Reads the sensors on their own thread, so a slow network never delays a sample.
+/// The first read waits for `phase`, and later ones keep to that offset.
+#[allow(clippy::too_many_arguments)]
+fn spawn_sampler(
+    mut sensors: Vec<Box<dyn Sensor + Send>>,
+    mut battery: Option<Battery>,
+    mut analog: Option<Analog>,
+    mut pacing: Pacing,
+    phase: Duration,
+    clock: Arc<Mutex<Clock>>,
+    health: SharedHealth,
+    out: Producer<Sample>,
+) -> Result<()> {
+    thread::Builder::new()
+        .name("sampler".into())
+        .stack_size(SAMPLER_STACK)
+        .spawn(move || {
+            let mut implausible = 0;
+            let mut due = Instant::now() + phase;
+            loop {
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                let started = Instant::now();
+                let mut measurements = Vec::new();
+                let mut read_ok = false;
+                for sensor in sensors.iter_mut() {
+                    match sensor.read() {
+                        Ok(readings) => {
+                            for m in readings {
+                                let Some(m) = screen_reading(m, &mut implausible) else {
+                                    continue;
+                                };
+                                read_ok |= m.quality & QUALITY_NO_SENSOR == 0;
+                                measurements.push(m);
+                            }
+                        }
+                        Err(e) => {
+                            log::warn!("Read error: {:?}", e);
+                            // A labeled sensor is reported failed on its own, next to the others
+                            if let Some(label) = sensor.label() {
+                                measurements.push(Measurement {
+                                    tag: Some(label.to_owned()),
+                                    quality: QUALITY_NO_SENSOR,
+                                    ..Default::default()
+                                });
+                            }
+                        }
+                    }
+                }
+                let read_ms = started.elapsed().as_millis() as u32;
+                if measurements.is_empty() {
+                    // Keep the ping going even when no sensor answered, without values
+                    measurements.push(Measurement { quality: QUALITY_NO_SENSOR, ..Default::default() });
+                }
+                let mut read_errors =
+                    sensors.iter().fold(ReadErrors::default(), |sum, s| sum.add(s.read_errors()));
+                read_errors.implausible = implausible;
+                {
+                    let mut health = health.lock().unwrap();
+                    health.read_errors = read_errors;
+                    if read_ok {
+                        health.last_read_ok = Some(Instant::now());
+                        health.last_reading = measurements
+                            .iter()
+                            .filter(|m| m.quality & QUALITY_NO_SENSOR == 0)
+                            .cloned()
+                            .collect();
+                    }
+                }
+
+                let battery_pct = battery.as_mut().and_then(|b| {
+                    b.read_pct().map_err(|e| log::warn!("Battery read error: {:?}", e)).ok()
+                });
+
+                let stamp = clock.lock().unwrap().now();
+                let interval = match &mut pacing {
+                    Pacing::Scheduled { schedule, base } => {
+                        schedule.interval_at(local_minute(&stamp), *base)
+                    }
+                    Pacing::Adaptive(adaptive) => adaptive.observe(&measurements),
+                };
+                let analog = analog.as_mut().and_then(|a| {
+                    a.read().map_err(|e| log::warn!("Analog read error: {:?}", e)).ok()
+                });
+
+                let sample = Sample {
+                    at: started,
+                    stamp,
+                    measurements,
+                    battery_pct,
+                    read_errors,
+                    analog,
+                    read_ms,
+                };
+                if !out.send(sample) {
+                    return;
+                }
+                // Counted from the last due time rather than the read, so the
+                // phase doesn't creep by the wake-up latency every cycle. A read
+                // that overran the interval starts a new phase.
+                due = (due + interval).max(Instant::now());
+            }
+        })
+        .context("Sampler thread")?;
+    Ok(())
+}
+
+/// Blocks until the setup page submits credentials, running the scans it asks
+/// for meanwhile.
+fn wait_for_credentials(
+    wifi: &mut EspWifi,
+    creds: &Receiver<SetupReq>,
+    scans: &Receiver<ScanRequest>,
+) -> SetupReq {
+    loop {
+        match creds.recv_timeout(Duration::from_millis(100)) {
+            Ok(req) => {
+                // `claim` lets one submission through at a time, so anything
+                // queued behind it is stale
+                let stale = creds.try_iter().count();
+                if stale > 0 {
+                    log::warn!("Dropped {stale} extra setup submission(s)");
+                }
+                return req;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("Channel closed"),
+        }
+        for reply in scans.try_iter() {
+            let _ = reply.send(scan_networks(wifi));
+        }
+    }
+}
+
+/// Where provisioning stands. A failed join goes back to `Setup`, so the page
+/// can take corrected credentials; `Running` hands the link over to `main`.
+enum Phase {
+    Setup,
+    Connecting(SetupReq),
+    Running(SetupReq),
+}
+
+/// Runs the setup AP and page until a submission connects, and returns the
+/// credentials that worked.
+fn provision(
+    wifi: &mut EspWifi,
+    cfg: &Config,
+    nvs_part: EspDefaultNvsPartition,
+    notice: Option<String>,
+    led: &StatusLed,
+) -> Result<SetupReq> {
+    let (tx, rx) = channel::<SetupReq>();
+    let (scan_tx, scans) = channel::<ScanRequest>();
+    let status: SharedSetup = Arc::new(Mutex::new(SetupStatus {
+        state: SetupState::Waiting,
+        ip_reported: false,
+        config_warnings: cfg.warnings,
+        auth_mode: None,
+    }));
+    let mut portal = None;
+    let mut phase = Phase::Setup;
+    loop {
+        phase = match phase {
+            Phase::Setup => {
+                // First pass, or AP-only mode, where joining took the AP down
+                if portal.is_none() {
+                    start_ap(wifi, cfg)?;
+                    let server = spawn_setup_server(
+                        tx.clone(),
+                        scan_tx.clone(),
+                        nvs_part.clone(),
+                        notice.clone(),
+                        status.clone(),
+                    )?;
+                    let dns = if CAPTIVE_PORTAL { Some(dns::spawn(cfg.ap_net.ip)?) } else { None };
+                    portal = Some((server, dns));
+                }
+                led.set(LedState::Setup);
+                log::info!("🖥️ Waiting for Wi-Fi credentials...");
+                // The handler that claimed provisioning already moved it to Connecting
+                let creds = wait_for_credentials(wifi, &rx, &scans);
+                // AP-only mode is done with the page, AP+STA keeps serving `/status`
+                if !SETUP_KEEP_AP {
+                    portal = None;
+                }
+                Phase::Connecting(creds)
+            }
+            Phase::Connecting(creds) => {
+                log::info!("📡 Connecting to '{}'", creds.ssid);
+                led.set(LedState::Connecting);
+                match connect_sta(wifi, &creds, cfg, SETUP_KEEP_AP) {
+                    Ok(ip) => {
+                        let auth_mode = negotiated_auth(wifi);
+                        let mut setup = status.lock().unwrap();
+                        setup.state = SetupState::Connected(ip);
+                        setup.auth_mode = auth_mode;
+                        Phase::Running(creds)
+                    }
+                    Err(e) => {
+                        log::warn!("Joining '{}' failed ({:?}), back to setup", creds.ssid, e);
+                        status.lock().unwrap().state = SetupState::Failed(e.to_string());
+                        if SETUP_KEEP_AP {
+                            // The AP stays up next to the idle station
+                            let _ = wifi.disconnect();
+                        } else {
+                            stop_wifi(wifi)?;
+                        }
+                        Phase::Setup
+                    }
+                }
+            }
+            Phase::Running(creds) => {
+                if SETUP_KEEP_AP {
+                    // Give the setup page time to show the link to the new address
+                    let connected_at = Instant::now();
+                    while !status.lock().unwrap().ip_reported
+                        && connected_at.elapsed() < SETUP_LINGER
+                    {
+                        thread::sleep(Duration::from_millis(500));
+                    }
+                    thread::sleep(Duration::from_secs(2));
+                    // The diagnostics server below needs the port back
+                    drop(portal);
+                    close_setup_ap(wifi, &creds, cfg)?;
+                }
+                return Ok(creds);
+            }
+        };
+    }
 }
 
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
     EspLogger::initialize_default();
+    let boot = Instant::now();
 
-    let peripherals = Peripherals::take().context("No peripherals")?;
+    let mut peripherals = Peripherals::take().context("No peripherals")?;
     let sysloop = EspSystemEventLoop::take().context("No sysloop")?;
+    let nvs_part = EspDefaultNvsPartition::take().context("No NVS")?;
+    let mut nvs = EspNvs::new(nvs_part.clone(), "mk2", true).context("NVS open")?;
+    let mut cfg = Config::load(&nvs);
+    apply_log_level(cfg.log_level, cfg.quiet);
+    if cfg.warnings > 0 {
+        log::warn!("⚠️ {} stored config field(s) invalid, defaults used", cfg.warnings);
+    }
+    // Kept for the whole run: tells a refused password from an AP out of reach
+    let _disconnects = sysloop.subscribe::<WifiEvent, _>(|event: WifiEvent| {
+        if let WifiEvent::StaDisconnected(disconnected) = event {
+            STA_DISCONNECT.store(disconnected.reason(), Ordering::Relaxed);
+        }
+    })?;
     let mut wifi = EspWifi::new(peripherals.modem, sysloop, None).context("Wi-Fi init")?;
+    wifi.swap_netif_ap(ap_netif(&cfg.ap_net).context("AP netif")?)?;
+    set_country(&cfg.wifi_country).context("Wi-Fi country")?;
 
-    // Mode AP + serveur de setup
-    start_ap(&mut wifi, "ESP32_SETUP")?;
-    let (tx, rx) = channel::<SetupReq>();
-    let server = spawn_setup_server(tx)?;
-    println!("🖥️ Waiting for Wi-Fi credentials...");
+    // Waking from deep sleep is a boot too, but not a crash
+    let crash_loop = if WakeupReason::get() == WakeupReason::Timer {
+        bootguard::CrashLoop { boots: 0, tripped: false }
+    } else {
+        bootguard::record_boot(&mut nvs, CRASH_LOOP_BOOTS, CRASH_LOOP_WINDOW_SECS)
+    };
+    let dead_man_setup = deadman::take_setup_request(&mut nvs);
+    let notice = if crash_loop.tripped {
+        let notice = format!(
+            "Safe mode: {} boots within {CRASH_LOOP_WINDOW_SECS}s. Check the configuration or wiring, then reconnect.",
+            crash_loop.boots
+        );
+        log::error!("🔁 Crash loop detected. {notice}");
+        Some(notice)
+    } else if dead_man_setup {
+        // Reopened by the dead-man timer: Wi-Fi worked, the server never answered
+        let notice = "No post got through for a long while although Wi-Fi was up. Check the server URL, then reconnect.";
+        log::error!("⏱️ Dead-man timer expired. {notice}");
+        Some(notice.to_owned())
+    } else {
+        None
+    };
+    let boot_count = bootguard::count_boot(&mut nvs);
+    let reset = ResetReason::get();
+    let reset_reason = bootguard::reset_reason_str(reset);
+    log::info!("🔌 Boot #{boot_count}, reset reason: {reset_reason}");
+    if reset == ResetReason::Brownout {
+        log::warn!("⚡ Last reset was a brownout, check the power supply");
+    }
+
+    let state_led = if STATE_LED {
+        StatusLed::spawn(peripherals.pins.gpio7.downgrade_output())
+    } else {
+        StatusLed::disabled()
+    };
+
+    // Safe mode always goes through the setup page so its notice is seen
+    let mut wifi_nvs = EspNvs::new(nvs_part.clone(), WIFI_NAMESPACE, true).context("NVS open")?;
+    let held = if FACTORY_RESET_BUTTON || SELF_TEST_BUTTON {
+        held_at_boot(&mut peripherals.pins.gpio9, FACTORY_RESET_HOLD)?
+    } else {
+        Duration::ZERO
+    };
+    let factory_reset = FACTORY_RESET_BUTTON && held >= FACTORY_RESET_HOLD;
+    let self_test = cfg!(feature = "selftest")
+        || (SELF_TEST_BUTTON && held >= SELF_TEST_HOLD && !factory_reset);
+    if self_test {
+        log::info!("🧪 Self-test requested, it runs once the network is up");
+    }
+    if factory_reset {
+        // Setup follows right away; rebooting would only find the button still held
+        log::info!("🧹 Factory reset: stored Wi-Fi credentials erased");
+        clear_credentials(&mut wifi_nvs);
+    }
+    // Both keep the stored network; a reboot without submitting uses it again
+    let skip_stored = crash_loop.tripped || dead_man_setup;
+    let stored = if skip_stored { None } else { load_credentials(&wifi_nvs) };
+    let had_stored = stored.is_some();
+    let mut joined = false;
+    if let Some(mut creds) = stored {
+        log::info!("📡 Connecting to stored network '{}'", creds.ssid);
+        state_led.set(LedState::Connecting);
+        creds.static_ip = cfg.static_ip;
+        for attempt in 1..=STORED_JOIN_ATTEMPTS {
+            match connect_sta(&mut wifi, &creds, &cfg, false) {
+                Ok(_) => joined = true,
+                // The network took us, only its DHCP is slow: the credentials are fine
+                Err(AppError::Dhcp(timeout)) if attempt < STORED_JOIN_ATTEMPTS => {
+                    log::warn!("No DHCP lease within {timeout:?} ({attempt}/{STORED_JOIN_ATTEMPTS})");
+                    continue;
+                }
+                Err(AppError::Dhcp(_)) => {
+                    log::warn!("Still no DHCP lease, setup for this boot; credentials kept");
+                    stop_wifi(&mut wifi).context("Wi-Fi not idle before setup")?;
+                }
+                // Only a restart can help a failing driver
+                Err(e @ (AppError::Wifi(_) | AppError::WifiStuck(_))) => return Err(e.into()),
+                // The only answer that proves the stored password wrong
+                Err(e @ AppError::Auth(_)) => {
+                    log::warn!("Stored credentials refused ({e}), back to setup");
+                    clear_credentials(&mut wifi_nvs);
+                    stop_wifi(&mut wifi).context("Wi-Fi not idle before setup")?;
+                }
+                // An AP that is off or out of range may well be back on the next boot
+                Err(e) => {
+                    log::warn!("Join failed ({e}), setup for this boot; credentials kept");
+                    stop_wifi(&mut wifi).context("Wi-Fi not idle before setup")?;
+                }
+            }
+            break;
+        }
+    }
+    if !joined {
+        let part = nvs_part.clone();
+        let creds = provision(&mut wifi, &cfg, part, notice, &state_led)?;
+        if let Err(e) = save_credentials(&mut wifi_nvs, &creds) {
+            log::warn!("Cannot store Wi-Fi credentials: {:?}", e);
+        }
+        // Kept as overrides, so later boots post there too
+        if let Some(url) = creds.url {
+            log::info!("💾 Telemetry URL set to {url}");
+            cfg.url = url;
+        }
+        if let Some(id) = creds.device_id {
+            log::info!("💾 Device name set to {id}");
+            cfg.device_id = Some(id);
+        }
+        if let Some(secs) = creds.interval_secs {
+            log::info!("💾 Sample interval set to {secs} s");
+            cfg.sample_interval = Duration::from_secs(secs);
+        }
+        if let Some(token) = creds.auth_token {
+            log::info!("💾 Auth token stored");
+            cfg.auth_token = Some(token);
+        }
+        // The setup page decides addressing: a blank address means DHCP from now on
+        match creds.static_ip {
+            Some(static_ip) => {
+                log::info!("💾 Static IP {}/{} stored", static_ip.ip, static_ip.prefix)
+            }
+            None if cfg.static_ip.is_some() => log::info!("💾 Static IP cleared, using DHCP"),
+            None => {}
+        }
+        cfg.static_ip = creds.static_ip;
+        if let Err(e) = cfg.save(&mut nvs) {
+            log::warn!("Cannot store the setup settings: {e}");
+        }
+        if !creds.settings.is_empty() {
+            for (name, value) in &creds.settings {
+                if let Err(e) = Config::store(&mut nvs, name, value) {
+                    log::warn!("Cannot store config '{name}': {e}");
+                }
+            }
+            log::info!("💾 {} imported config setting(s) stored", creds.settings.len());
+            // Picks them up now; the Wi-Fi country only on the next boot
+            cfg = Config::load(&nvs);
+        }
+    }
+    let device_id = DEVICE.get_or_init(|| cfg.device_id.clone().unwrap_or_else(mac_device_id));
+    log::info!("🏷️ Device id: {device_id}");
+    let auth_mode = negotiated_auth(&mut wifi);
+    let deep_sleep = DEEP_SLEEP && load_credentials(&wifi_nvs).is_some();
+    if DEEP_SLEEP && !deep_sleep {
+        log::warn!("Deep sleep needs stored credentials, staying awake this boot");
+    }
 
-    let creds = rx.recv().expect("Channel closed");
-    drop(server);
+    log::info!("✅ Connected ({})! Starting ADC read loop...", auth_mode.unwrap_or("auth unknown"));
 
-    println!("📡 Connecting to '{}'", creds.ssid);
-    connect_sta(&mut wifi, &creds.ssid, &creds.pass)?;
+    // Only reached on a working link, so keep this image
+    ota::mark_running_valid();
 
-    println!("✅ Connected! Starting ADC read loop...");
+    let (command_tx, commands) = channel::<Command>();
+    if REPROVISION_BUTTON {
+        // Only a boot that came through setup starts the cooldown
+        let guard = reprovision::Guard::new(
+            (!joined).then(Instant::now),
+            REPROVISION_COOLDOWN,
+            REPROVISION_LONG_PRESS,
+            REPROVISION_CONFIRM,
+        );
+        let led = if STATUS_LED { Some(peripherals.pins.gpio8.downgrade_output()) } else { None };
+        // Without stored credentials the next boot opens the setup AP
+        let tx = command_tx.clone();
+        let button = peripherals.pins.gpio9.downgrade();
+        let watch = reprovision::spawn_watch(button, led, guard, move || {
+            clear_credentials(&mut wifi_nvs);
+            let _ = tx.send(Command::Reboot);
+        });
+        if let Err(e) = watch {
+            log::warn!("Re-provisioning button unavailable: {:?}", e);
+        }
+    }
 
+    let clock = Clock::new(boot);
+    if clock.wait_for_ntp(NTP_SYNC_TIMEOUT) {
+        log::info!("🕒 Clock synced over NTP");
+    } else {
+        log::warn!("No NTP sync within {:?}, using uptime until it arrives", NTP_SYNC_TIMEOUT);
+    }
+    let clock = Arc::new(Mutex::new(clock));
+    let health: SharedHealth =
+        Arc::new(Mutex::new(Health { boot_count, reset_reason, ..Health::default() }));
+    let _diag_server = diag::spawn_diag_server(health.clone())?;
+    // Nice to have: the device works the same without it
+    let _mdns = advertise_mdns(&mdns_hostname(device_id), device_id)
+        .map_err(|e| log::warn!("mDNS unavailable, reach the device by IP: {:?}", e))
+        .ok();
 
-    let mut pin = PinDriver::input_output_od(peripherals.pins.gpio4)?;
-    pin.set_pull(Pull::Up)?;
+    let spacing = (cfg.sample_interval / AVERAGE_WINDOW.max(1)).max(DHT_RETRY_SPACING);
+    if AVERAGING {
+        log::info!("🧮 Averaging {AVERAGE_WINDOW} reads, {spacing:?} apart");
+    }
+    let mut sensors: Vec<Box<dyn Sensor + Send>> = Vec::new();
+    for (label, pin) in dht_pins!(peripherals) {
+        let gpio = pin.pin();
+        let mut dht = DhtSensor::new(
+            pin,
+            cfg.sensor,
+            SLOW_READ_US,
+            CHECKSUM_CORRECTION,
+            DHT_READ_ATTEMPTS,
+            DHT_RETRY_SPACING,
+        )?;
+        let name = cfg.sensor.name();
+        if label.is_empty() {
+            log::info!("🌡️ {name} on GPIO{gpio}");
+        } else {
+            dht = dht.labeled(label);
+            log::info!("🌡️ {name} '{label}' on GPIO{gpio}");
+        }
+        sensors.push(if AVERAGING {
+            Box::new(Averaged::new(dht, AVERAGE_WINDOW, spacing))
+        } else {
+            Box::new(dht)
+        });
+    }
+    #[cfg(feature = "ds18b20")]
+    sensors.push(Box::new(sensor::Ds18b20Bus::new(peripherals.pins.gpio5.downgrade())?));
+
+    if self_test {
+        let mut report = selftest::Report::default();
+        report.record("sensor", selftest::check_sensors(&mut sensors));
+        // Joining through the setup page proves the radio, not the stored network
+        let wifi_check = match wifi.driver_mut().get_ap_info() {
+            Ok(ap) if joined => selftest::Outcome::Pass(format!(
+                "'{}', RSSI {} dBm",
+                ap.ssid, ap.signal_strength
+            )),
+            _ if had_stored => selftest::Outcome::Fail("stored network did not join".into()),
+            _ => selftest::Outcome::Fail("no stored network".into()),
+        };
+        report.record("wifi", wifi_check);
+        report.record("dns", selftest::check_dns(&cfg.url));
+        let post = if transport::is_mqtt(&cfg.url) {
+            selftest::Outcome::Skip("MQTT endpoint, nothing to POST".into())
+        } else {
+            let signer =
+                SIGNING_KEY.map(|key| Signer { key: key.as_bytes(), clock: clock.clone() });
+            let mut http = HttpTransport::new(&cfg.url, signer, cfg.auth_token.as_deref())
+                .request_with(cfg.http_method, cfg.http_headers.clone());
+            let body = serde_json::json!({ "self_test": true, "device_id": device_id });
+            selftest::check_post(&mut http, &body.to_string())
+        };
+        report.record("post", post);
+        report.log();
+        state_led.set(if report.passed() { LedState::Online } else { LedState::Error });
+        thread::sleep(SELF_TEST_SHOW);
+    }
 
-    let mut delay = Ets;
+    let schedule = Schedule::parse(&cfg.schedule).unwrap_or_else(|e| {
+        log::warn!("{e}, using a fixed interval");
+        Schedule::default()
+    });
+    let policy = Policy::parse(BACKPRESSURE, Duration::from_millis(BACKPRESSURE_BLOCK_MS))
+        .unwrap_or_else(|| {
+            log::warn!("Unknown backpressure policy '{BACKPRESSURE}', using drop_oldest");
+            Policy::DropOldest
+        });
+    let (producer, samples) = backpressure::channel(SAMPLE_QUEUE, policy);
+    // One ADC1 driver, shared by the battery monitor and the analog input
+    let adc1 = if VBAT_MONITOR || ANALOG {
+        AdcDriver::new(peripherals.adc1)
+            .map(Arc::new)
+            .map_err(|e| log::warn!("ADC1 unavailable: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
+    let battery = match &adc1 {
+        Some(adc) if VBAT_MONITOR => {
+            Battery::new(adc.clone(), peripherals.pins.gpio3, VBAT_DIVIDER, BATTERY_CURVE)
+                .map_err(|e| log::warn!("Battery monitor unavailable: {:?}", e))
+                .ok()
+        }
+        _ => None,
+    };
+    let analog = match &adc1 {
+        Some(adc) if ANALOG => {
+            let pin = analog_pin!(peripherals);
+            Analog::new(adc.clone(), pin, cfg.analog_scale, cfg.analog_offset)
+                .map_err(|e| log::warn!("Analog input unavailable: {:?}", e))
+                .ok()
+        }
+        _ => None,
+    };
+    // Slowest the sampler can get, and the longest a sample may then wait to go out
+    let read_every = if ADAPTIVE { ADAPTIVE_MAX } else { schedule.longest(cfg.sample_interval) };
+    let mut post_every = cfg.report_interval.unwrap_or(read_every);
+    if BATCH_MAX_COUNT > 1 {
+        post_every = post_every.max(Duration::from_secs(BATCH_MAX_AGE_SECS));
+    }
+    health.lock().unwrap().freshness = Freshness::new(read_every, post_every);
+    let pacing = if ADAPTIVE {
+        Pacing::Adaptive(Adaptive::new(
+            ADAPTIVE_MIN,
+            ADAPTIVE_MAX,
+            ADAPTIVE_FAST_DELTA,
+            ADAPTIVE_STEADY_DELTA,
+        ))
+    } else {
+        Pacing::Scheduled { schedule, base: cfg.sample_interval }
+    };
+    let phase = startup_phase(cfg.sample_interval);
+    spawn_sampler(
+        sensors,
+        battery,
+        analog,
+        pacing,
+        phase,
+        clock.clone(),
+        health.clone(),
+        producer,
+    )?;
+
+    let mut cycle: u32 = 0;
+    let mut boot_stable = false;
+    let mut batch = Batch::new(BATCH_MAX_COUNT, Duration::from_secs(BATCH_MAX_AGE_SECS));
+    // History ids of the samples `batch` holds
+    let mut batched = 0..0;
+    let template = cfg.payload_template.as_deref().and_then(|text| {
+        Template::parse(text).inspect_err(|e| log::warn!("Payload template: {e}")).ok()
+    });
+    let fields = FieldFilter::new(TELEMETRY_FIELDS)
+        .unwrap_or_else(|e| {
+            log::warn!("{e}, sending every field");
+            FieldFilter::new(&[]).unwrap()
+        })
+        .with_template(template);
+    #[cfg(feature = "sdcard")]
+    let mut sd_card = if cfg.sd_log {
+        let (spi, sclk, mosi, miso, cs) = sd_pins!(peripherals);
+        sdlog::SdTransport::mount(spi, sclk, mosi, miso, cs, clock.clone())
+            .map_err(|e| log::warn!("SD card unavailable, SD logging off: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
+    let mut sinks: Vec<Sink> = Vec::new();
+    for name in SINKS {
+        let transport: Box<dyn Transport> = match *name {
+            // An mqtt(s):// URL publishes to the broker instead of posting
+            "http" if transport::is_mqtt(&cfg.url) => {
+                let topic = format!("mk2/{device_id}/telemetry");
+                Box::new(MqttTransport::new(&cfg.url, topic))
+            }
+            "http" => {
+                let signer =
+                    SIGNING_KEY.map(|key| Signer { key: key.as_bytes(), clock: clock.clone() });
+                // A template need not render JSON, so there is nothing to re-encode
+                let encoding = match cfg.encoding {
+                    Encoding::Cbor if cfg.payload_template.is_some() => {
+                        log::warn!("Payload template set, sending it as is instead of CBOR");
+                        Encoding::Json
+                    }
+                    encoding => encoding,
+                };
+                let http = HttpTransport::new(&cfg.url, signer, cfg.auth_token.as_deref())
+                    .request_with(cfg.http_method, cfg.http_headers.clone())
+                    .encoding(encoding)
+                    .falling_back(cfg.fallback_urls.clone())
+                    .reusing(HTTP_KEEP_ALIVE)
+                    .streaming(HTTP_STREAM_BATCHES);
+                Box::new(http)
+            }
+            "sd" if !cfg.sd_log => continue,
+            #[cfg(feature = "sdcard")]
+            "sd" => match sd_card.take() {
+                Some(sd) => Box::new(sd),
+                None => continue,
+            },
+            #[cfg(not(feature = "sdcard"))]
+            "sd" => {
+                log::warn!("SD logging needs a build with the sdcard feature");
+                continue;
+            }
+            other => {
+                log::warn!("Unknown sink '{other}', skipped");
+                continue;
+            }
+        };
+        let outbox = Outbox::new(OUTBOX_CAPACITY, POISON_MAX_REJECTS);
+        let backoff = Backoff::new(POST_BACKOFF_BASE, POST_BACKOFF_CAP);
+        sinks.push(Sink::new(transport, outbox, backoff));
+    }
+    persist::buffer_restore(&mut nvs, &mut sinks);
+    if let Some(commands_url) = COMMANDS_URL {
+        commands::spawn_long_poll(commands_url, command_tx)?;
+    }
+    let mut relay = if RELAY {
+        PinDriver::output(peripherals.pins.gpio6.downgrade_output())
+            .map_err(|e| log::warn!("Relay pin unavailable: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
 
-    let url = "http://b15ca8fb2839.ngrok-free.app/ping";
+    let mut aggregator = Aggregator::default();
+    let mut last_env: Option<Envelope> = None;
+    let mut next_report = Instant::now() + phase + cfg.report_interval.unwrap_or_default();
+    let mut last_offer: Option<String> = None;
+    let mut next_link_check = Instant::now();
+    let mut metrics = Metrics::new(METRICS_WINDOW);
+    // Once per run of failures: after that the MAX_POST_FAILURES reboot is next
+    let mut wifi_rebuilt = false;
+    let mut dead_man = cfg.dead_man.map(|timeout| deadman::DeadMan::load(&nvs, timeout));
+    let mut next_heartbeat = Instant::now() + cfg.heartbeat_interval.unwrap_or_default();
+    let mut watchdog = match WATCHDOG_TIMEOUT {
+        Some(duration) => {
+            let config = TWDTConfig { duration, panic_on_trigger: true, ..TWDTConfig::new() };
+            Some(TWDTDriver::new(peripherals.twdt, &config)?)
+        }
+        None => None,
+    };
+    let mut watch = match watchdog.as_mut() {
+        Some(driver) => Some(driver.watch_current_task()?),
+        None => None,
+    };
     loop {
-        let mut temperature_value: i8 = 0;
-        let mut humidity_value: u8 = 0;
+        if let Some(watch) = watch.as_mut() {
+            let _ = watch.feed();
+        }
+        // Wake for the next sample, or earlier when the batch ages out
+        let now = Instant::now();
+        let mut wait = batch.time_to_due(now).unwrap_or(cfg.sample_interval);
+        if let Some(next) = sinks.iter().filter_map(Sink::next_attempt).min() {
+            wait = wait.min(next.saturating_duration_since(now));
+        }
+        if cfg.report_interval.is_some() {
+            wait = wait.min(next_report.saturating_duration_since(now));
+        }
+        if cfg.heartbeat_interval.is_some() {
+            wait = wait.min(next_heartbeat.saturating_duration_since(now));
+        }
+        if COMMANDS_URL.is_some() || REPROVISION_BUTTON {
+            wait = wait.min(COMMAND_TICK);
+        }
+        // Idle waits alone must never starve the watchdog
+        if let Some(timeout) = WATCHDOG_TIMEOUT {
+            wait = wait.min(timeout / 2);
+        }
+        match samples.recv_timeout(wait) {
+            Ok(sample) => {
+                if let Some(discovery_url) = DISCOVERY_URL {
+                    if cycle % DISCOVERY_EVERY_CYCLES == 0 {
+                        if let Some(endpoint) = discovery::discover_endpoint(&mut nvs, discovery_url) {
+                            sinks.iter_mut().for_each(|sink| sink.set_endpoint(&endpoint));
+                        }
+                    }
+                }
+                if let Some(firmware_url) = FIRMWARE_URL {
+                    if cycle % OTA_EVERY_CYCLES == 0 {
+                        check_and_apply_ota(firmware_url, &health, &mut nvs, &mut sinks, &mut batch);
+                    }
+                }
+                cycle = cycle.wrapping_add(1);
+
+                if !boot_stable && boot.elapsed() > Duration::from_secs(CRASH_LOOP_WINDOW_SECS) {
+                    bootguard::mark_stable(&mut nvs);
+                    boot_stable = true;
+                }
 
-        match dht11::Reading::read(&mut delay, &mut pin) {
-            Ok(dht11::Reading { temperature, relative_humidity }) => {
-                log::info!("Temp: {} °C, Humidity: {} %", temperature, relative_humidity);
-                temperature_value = temperature;
-                humidity_value =relative_humidity;
+                let env = Envelope {
+                    stamp: sample.stamp,
+                    battery_pct: sample.battery_pct,
+                    dropped: samples.dropped(),
+                    samples: None,
+                    auth_mode: if REPORT_NET_STATS { negotiated_auth(&mut wifi) } else { None },
+                    unit: cfg.unit,
+                    boot_count,
+                    reset_reason,
+                    read_errors: sample.read_errors,
+                    analog: sample.analog,
+                    read_ms: sample.read_ms,
+                    metrics: metrics.report(),
+                };
+                let id = health.lock().unwrap().history.record(sample.stamp, &sample.measurements);
+                for m in &sample.measurements {
+                    match m.humidity {
+                        Some(h) => {
+                            log::info!(target: CYCLE_LOG, "Temp: {} °C, Humidity: {} %", m.temperature, h)
+                        }
+                        None if m.quality & QUALITY_NO_SENSOR != 0 => match &m.tag {
+                            Some(tag) => log::warn!("Sensor '{tag}' did not answer"),
+                            None => log::warn!("No sensor answered"),
+                        },
+                        None => log::info!(target: CYCLE_LOG, "Temp: {} °C", m.temperature),
+                    }
+                    if cfg.report_interval.is_none() {
+                        batch.push(measurement_json(m, &env, &fields)?, sample.at);
+                    } else if m.quality & QUALITY_NO_SENSOR == 0 {
+                        aggregator.push(m.tag.as_deref(), m.temperature, m.humidity);
+                    }
+                }
+                if cfg.report_interval.is_none() {
+                    batched.end = id.wrapping_add(1);
+                }
+                last_env = Some(env);
             }
-            Err(e) => {
-                log::warn!("Read error: {:?}", e);
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("Sampler thread stopped")),
+        }
+
+        // Reports carry the mean of the samples taken since the previous one
+        if let (Some(report_interval), Some(env)) = (cfg.report_interval, last_env.as_mut()) {
+            if Instant::now() >= next_report {
+                next_report += report_interval;
+                let now = Instant::now();
+                let summaries = aggregator.take();
+                // A report stands for every sample taken since the previous one
+                {
+                    let history = &mut health.lock().unwrap().history;
+                    let reported_to = history.next_id();
+                    if summaries.is_empty() && batch.is_empty() {
+                        // No sensor answered all along, so those samples never go out
+                        history.drop_samples(batched.end..reported_to);
+                        batched = reported_to..reported_to;
+                    } else {
+                        batched.end = reported_to;
+                    }
+                }
+                for summary in summaries {
+                    let m = Measurement {
+                        tag: summary.tag,
+                        temperature: summary.temperature,
+                        humidity: summary.humidity,
+                        ..Default::default()
+                    };
+                    env.samples = Some(summary.samples);
+                    batch.push(measurement_json(&m, env, &fields)?, now);
+                }
             }
         }
 
-        // HTTP POST
-        let conn = EspHttpConnection::new(&HttpCfg::default())?;
-        let mut client = embedded_svc::http::client::Client::wrap(conn);
+        if Instant::now() >= next_link_check {
+            let mut feed = || {
+                if let Some(watch) = watch.as_mut() {
+                    let _ = watch.feed();
+                }
+            };
+            let link = ensure_wifi_connected(&mut wifi, &mut feed);
+            // Wi-Fi outages have their own handling below, the dead-man timer is for the server
+            if !matches!(link, Ok(false)) {
+                if let Some(dead_man) = dead_man.as_mut() {
+                    dead_man.reset(&mut nvs);
+                }
+            }
+            match link {
+                Ok(false) => {}
+                Ok(true) => metrics.wifi_reconnects += 1,
+                // Leaves the half-open link, so the next round starts from a fresh association
+                Err(AppError::Dhcp(_)) => {
+                    log::error!("Wi-Fi associated but DHCP gave no address, dropping the link");
+                    let _ = wifi.disconnect();
+                    next_link_check = Instant::now() + WIFI_RECONNECT_PAUSE;
+                }
+                Err(AppError::Association) => {
+                    log::error!(
+                        "Wi-Fi unreachable after {WIFI_RECONNECT_ATTEMPTS} tries, offline for {:?}",
+                        WIFI_RECONNECT_PAUSE
+                    );
+                    next_link_check = Instant::now() + WIFI_RECONNECT_PAUSE;
+                }
+                Err(e) => {
+                    log::error!("🚨 {e}, restarting");
+                    planned_restart(&mut nvs, &mut sinks, &mut batch);
+                }
+            }
+        }
 
-        let payload = format!(
-            r#"{{"ping":true,"temperature":{},"humidity":{}}}"#,
-            temperature_value, humidity_value
-        );
+        let mut post_now = false;
+        while let Ok(command) = commands.try_recv() {
+            log::info!("📥 Command: {:?}", command);
+            match command {
+                Command::Relay { on } => match relay.as_mut() {
+                    Some(pin) => {
+                        if let Err(e) = pin.set_level(on.into()) {
+                            log::warn!("Relay switch failed: {:?}", e);
+                        }
+                    }
+                    None => log::warn!("Relay command ignored, no relay configured"),
+                },
+                Command::Reboot => planned_restart(&mut nvs, &mut sinks, &mut batch),
+                Command::PostNow => post_now = true,
+                Command::Config { key, value } => match Config::store(&mut nvs, &key, &value) {
+                    Ok(()) => log::info!("💾 Config '{key}' stored, applies after a reboot"),
+                    Err(e) => log::warn!("Config command rejected: {e}"),
+                },
+            }
+        }
 
-        let mut req = client.request(
-            Method::Post,
-            url,
-            &[("Content-Type", "application/json")],
-        )?;
+        if let Some(floor) = CRITICAL_HEAP {
+            let free = unsafe { sys::esp_get_free_heap_size() };
+            if free < floor {
+                log::error!("🚨 Free heap {free} B below the {floor} B floor, restarting cleanly");
+                low_heap_restart(free, &mut nvs, &mut sinks, &mut batch);
+            }
+        }
 
-        req.write_all(payload.as_bytes())?;
+        if !batch.is_empty() && (post_now || deep_sleep || batch.is_due(Instant::now())) {
+            push_batch(&mut batch, &mut sinks, batched.clone());
+            batched.start = batched.end;
+        }
+        if let Some(heartbeat_interval) = cfg.heartbeat_interval {
+            let now = Instant::now();
+            if now >= next_heartbeat {
+                // From now, so a long stall doesn't fire a burst of them
+                next_heartbeat = now + heartbeat_interval;
+                push_heartbeat(&mut sinks);
+            }
+        }
+        // Fresh payloads go out at once, unless the sink is backing off
+        let mut offered = None;
+        let (mut any_delivered, mut any_failed) = (false, false);
+        for sink in sinks.iter_mut() {
+            let now = Instant::now();
+            let due = sink.next_attempt().is_some_and(|at| at <= now);
+            let delivered = sink.flush();
+            let settled = sink.take_settled();
+            // The card taking a reading says nothing about the link
+            if sink.is_local() {
+                continue;
+            }
+            // A payload held back, e.g. for want of a synced clock, was never tried
+            let tried = due && !sink.is_holding();
+            if tried {
+                metrics.record_post(sink.is_empty(), now.elapsed());
+            }
+            any_delivered |= delivered > 0;
+            any_failed |= tried && delivered == 0;
+            {
+                let mut health = health.lock().unwrap();
+                health.history.settle(&settled);
+                if delivered > 0 {
+                    health.last_post_ok = Some(Instant::now());
+                }
+                if sink.last_status().is_some() {
+                    health.last_post_status = sink.last_status();
+                }
+            }
+            if let Some(date) = sink.take_server_date() {
+                clock.lock().unwrap().observe_http_date(&date);
+            }
+            offered = sink.take_firmware_url().or(offered);
+        }
+        // The server keeps offering until it sees the new version, so try each URL once
+        if let Some(url) = offered.filter(|url| last_offer.as_ref() != Some(url)) {
+            check_and_apply_ota(&url, &health, &mut nvs, &mut sinks, &mut batch);
+            last_offer = Some(url);
+        }
+        sinks.retain(|sink| !sink.gave_up());
+        if let Some(dead_man) = dead_man.as_mut() {
+            if any_delivered {
+                dead_man.reset(&mut nvs);
+            } else if any_failed && wifi.is_connected().unwrap_or(false) {
+                if dead_man.failed(&mut nvs, Instant::now()) {
+                    log::error!(
+                        "🚨 No post delivered in {:?} with Wi-Fi up, reopening setup",
+                        dead_man.failing()
+                    );
+                    deadman::request_setup(&mut nvs);
+                    planned_restart(&mut nvs, &mut sinks, &mut batch);
+                }
+            }
+        }
+        if any_delivered {
+            wifi_rebuilt = false;
+        }
+        let streak = sinks.iter().filter(|s| !s.is_local()).map(Sink::failure_streak).min();
+        let wedged = cfg.wifi_rebuild.zip(streak).is_some_and(|(after, streak)| streak >= after);
+        if wedged && !wifi_rebuilt && wifi.is_connected().unwrap_or(false) {
+            wifi_rebuilt = true;
+            log::error!("🚨 Every send failing with Wi-Fi up, restarting the Wi-Fi driver");
+            let mut feed = || {
+                if let Some(watch) = watch.as_mut() {
+                    let _ = watch.feed();
+                }
+            };
+            match rebuild_wifi(&mut wifi, &mut feed) {
+                Ok(_) => metrics.wifi_reconnects += 1,
+                // Left to the usual link check, which tries again
+                Err(e @ (AppError::Association | AppError::Dhcp(_))) => {
+                    log::error!("Wi-Fi not back after the restart: {e}");
+                }
+                Err(e) => {
+                    log::error!("🚨 {e}, restarting");
+                    planned_restart(&mut nvs, &mut sinks, &mut batch);
+                }
+            }
+        }
+        let mut network = sinks.iter().filter(|sink| !sink.is_local());
+        let stuck = network.clone().all(|sink| sink.failure_streak() >= MAX_POST_FAILURES);
+        if stuck && network.next().is_some() {
+            log::error!("🚨 {MAX_POST_FAILURES} failed flushes in a row on all sinks, restarting");
+            planned_restart(&mut nvs, &mut sinks, &mut batch);
+        }
 
-        let resp = req.submit()?;
-        println!("📨 Status: {}", resp.status());
+        let led_state = {
+            let mut health = health.lock().unwrap();
+            health.connected = wifi.is_connected().unwrap_or(false);
+            let ip = wifi.sta_netif().get_ip_info().ok().map(|info| info.ip);
+            health.ip = ip.filter(|ip| !ip.is_unspecified());
+            health.ssid = wifi.driver_mut().get_ap_info().ok().map(|ap| ap.ssid.to_string());
+            match health.check(Instant::now()) {
+                Ok(()) => LedState::Online,
+                Err(_) if !health.connected => LedState::Connecting,
+                Err(_) => LedState::Error,
+            }
+        };
+        state_led.set(led_state);
 
-        thread::sleep(Duration::from_secs(10));
+        // One sample taken and a send tried: what didn't go out waits in NVS
+        if deep_sleep && cycle > 0 {
+            sleep_until_next_reading(&mut nvs, &mut sinks, &mut batch, &mut wifi);
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    fn shared_setup() -> SharedSetup {
+        Arc::new(Mutex::new(SetupStatus {
+            state: SetupState::Waiting,
+            ip_reported: false,
+            config_warnings: 0,
+            auth_mode: None,
+        }))
+    }
+
+    #[test]
+    fn one_concurrent_claim_wins() {
+        let status = shared_setup();
+        let barrier = Arc::new(Barrier::new(2));
+        let submitters: Vec<_> = (0..2)
+            .map(|_| {
+                let (status, barrier) = (status.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    status.lock().unwrap().claim()
+                })
+            })
+            .collect();
+        let won: Vec<bool> = submitters.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(won.iter().filter(|&&w| w).count(), 1);
+        assert!(matches!(status.lock().unwrap().state, SetupState::Connecting));
+    }
+
+    #[test]
+    fn failed_attempt_frees_the_claim() {
+        let status = shared_setup();
+        let mut status = status.lock().unwrap();
+        assert!(status.claim());
+        assert!(!status.claim());
+        status.state = SetupState::Failed("wrong password".into());
+        assert!(status.claim());
+        status.state = SetupState::Connected(Ipv4Addr::new(192, 168, 1, 20));
+        assert!(!status.claim());
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The device's own health as sent with each payload.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report {
+    pub post_ms: Option<u32>,
+    pub success_pct: Option<u8>,
+    pub wifi_reconnects: u32,
+}
+
+/// Counters behind `Report`: the last post's duration, the outcome of the
+/// last `window` flushes and the Wi-Fi reconnects since boot.
+pub struct Metrics {
+    // Newest last
+    outcomes: VecDeque<bool>,
+    window: usize,
+    last_post_ms: Option<u32>,
+    pub wifi_reconnects: u32,
+}
+
+impl Metrics {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        let outcomes = VecDeque::with_capacity(window);
+        Self { outcomes, window, last_post_ms: None, wifi_reconnects: 0 }
+    }
+
+    /// Records one flush that tried to send; `ok` when it left nothing queued.
+    pub fn record_post(&mut self, ok: bool, took: Duration) {
+        if self.outcomes.len() == self.window {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(ok);
+        self.last_post_ms = Some(took.as_millis().min(u32::MAX as u128) as u32);
+    }
+
+    pub fn report(&self) -> Report {
+        let ok = self.outcomes.iter().filter(|&&ok| ok).count();
+        let success_pct =
+            (!self.outcomes.is_empty()).then(|| (ok * 100 / self.outcomes.len()) as u8);
+        Report { post_ms: self.last_post_ms, success_pct, wifi_reconnects: self.wifi_reconnects }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::transport::http_config;
+use anyhow::{anyhow, Result};
+use ed25519_compact::{PublicKey, Signature};
+use embedded_svc::http::client::Client;
+use embedded_svc::ota::FirmwareInfo;
+use esp_idf_svc::http::client::{Configuration as HttpCfg, EspHttpConnection};
+use esp_idf_svc::ota::{EspFirmwareInfoLoad, EspOta};
+
+// Ed25519 key the firmware images are signed with, e.g.
+// `openssl pkeyutl -sign -rawin -inkey ota.pem -in mk2.bin -out mk2.bin.sig`.
+// Replace with your own public key; the placeholder rejects every image.
+const OTA_PUBLIC_KEY: [u8; PublicKey::BYTES] = [0; PublicKey::BYTES];
+
+#[derive(Clone, Debug, Default)]
+pub enum OtaState {
+    #[default]
+    Idle,
+    UpToDate,
+    Applied,
+    Rejected(String),
+    Failed(String),
+}
+
+impl OtaState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OtaState::Idle => "idle",
+            OtaState::UpToDate => "up_to_date",
+            OtaState::Applied => "applied",
+            OtaState::Rejected(_) => "rejected",
+            OtaState::Failed(_) => "failed",
+        }
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            OtaState::Rejected(d) | OtaState::Failed(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+fn fetch_signature(sig_url: &str) -> Result<Signature> {
+    let mut client = Client::wrap(EspHttpConnection::new(&http_config(sig_url))?);
+    let mut resp = client.get(sig_url)?.submit()?;
+    if resp.status() != 200 {
+        return Err(anyhow!("Signature HTTP {}", resp.status()));
+    }
+    let mut sig = [0u8; Signature::BYTES];
+    let mut len = 0;
+    while len < sig.len() {
+        let n = resp.read(&mut sig[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    Signature::from_slice(&sig[..len]).map_err(|_| anyhow!("Malformed signature ({len} bytes)"))
+}
+
+fn running_version(ota: &EspOta) -> Option<String> {
+    let slot = ota.get_running_slot().ok()?;
+    slot.firmware.map(|f| f.version.as_str().to_owned())
+}
+
+/// Streams the image at `url` into the next OTA slot while checking its
+/// detached signature at `<url>.sig`. The slot is only made bootable when the
+/// signature verifies; otherwise the update is aborted and the running image
+/// stays in place.
+pub fn apply_signed_ota(url: &str) -> OtaState {
+    match try_apply(url) {
+        Ok(state) => state,
+        Err(e) => OtaState::Failed(format!("{e:#}")),
+    }
+}
+
+fn try_apply(url: &str) -> Result<OtaState> {
+    let signature = fetch_signature(&format!("{url}.sig"))?;
+    let mut verifier = match PublicKey::new(OTA_PUBLIC_KEY).verify_incremental(&signature) {
+        Ok(v) => v,
+        Err(e) => return Ok(OtaState::Rejected(format!("Unusable signature: {e}"))),
+    };
+
+    let mut ota = EspOta::new()?;
+    let current = running_version(&ota);
+
+    let mut client = Client::wrap(EspHttpConnection::new(&HttpCfg {
+        buffer_size: Some(4096),
+        ..http_config(url)
+    })?);
+    let mut resp = client.get(url)?.submit()?;
+    if resp.status() != 200 {
+        return Err(anyhow!("Firmware HTTP {}", resp.status()));
+    }
+    let expected = resp.header("Content-Length").and_then(|v| v.parse::<usize>().ok());
+
+    // Dropping `update` on any early return aborts the write
+    let mut update = ota.initiate_update()?;
+    let mut head = Vec::with_capacity(512);
+    let mut checked = false;
+    let mut buf = [0u8; 1024];
+    let mut written = 0;
+    loop {
+        let n = resp.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let chunk = &buf[..n];
+        if !checked {
+            head.extend_from_slice(chunk);
+            let mut info = FirmwareInfo {
+                version: Default::default(),
+                released: Default::default(),
+                description: None,
+                signature: None,
+                download_id: None,
+            };
+            if EspFirmwareInfoLoad.fetch(&head, &mut info)? {
+                checked = true;
+                if current.as_deref() == Some(info.version.as_str()) {
+                    update.abort()?;
+                    return Ok(OtaState::UpToDate);
+                }
+                log::info!("⬇️ OTA {} → {}", current.as_deref().unwrap_or("?"), info.version);
+            }
+        }
+        verifier.absorb(chunk);
+        update.write(chunk)?;
+        written += n;
+    }
+    // A connection closed early must never reach `complete`
+    if let Some(len) = expected.filter(|&len| len != written) {
+        update.abort()?;
+        return Err(anyhow!("Download cut short ({written} of {len} bytes)"));
+    }
+
+    if let Err(e) = verifier.verify() {
+        update.abort()?;
+        return Ok(OtaState::Rejected(format!("Signature check failed: {e}")));
+    }
+    update.complete()?;
+    Ok(OtaState::Applied)
+}
+
+/// Confirms the running image so the bootloader doesn't roll it back.
+pub fn mark_running_valid() {
+    if let Err(e) = EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        log::warn!("Cannot mark running slot valid: {:?}", e);
+    }
+}
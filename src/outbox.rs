@@ -0,0 +1,203 @@
+use crate::error::AppError;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::iter;
+use std::ops::Range;
+
+/// A queued body: one JSON document, or readings posted together as a JSON
+/// array. The array keeps its readings apart, so it is only ever joined into
+/// one string for a transport that needs the body whole.
+#[derive(Clone)]
+pub enum Payload {
+    One(String),
+    Array(Vec<String>),
+}
+
+impl Payload {
+    /// The body in pieces, in order; brackets and commas are pieces too.
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        let (open, items, close) = match self {
+            Self::One(body) => ("", std::slice::from_ref(body), ""),
+            Self::Array(items) => ("[", items.as_slice(), "]"),
+        };
+        let items = items
+            .iter()
+            .enumerate()
+            .flat_map(|(i, item)| [if i == 0 { "" } else { "," }, item.as_str()]);
+        iter::once(open).chain(items).chain(iter::once(close)).filter(|c| !c.is_empty())
+    }
+
+    /// Body length in bytes, without joining it.
+    #[cfg_attr(not(feature = "gzip"), allow(dead_code))]
+    pub fn size(&self) -> usize {
+        self.chunks().map(str::len).sum()
+    }
+
+    pub fn joined(&self) -> Cow<'_, str> {
+        match self {
+            Self::One(body) => Cow::Borrowed(body),
+            Self::Array(_) => Cow::Owned(self.chunks().collect()),
+        }
+    }
+}
+
+impl From<String> for Payload {
+    fn from(body: String) -> Self {
+        Self::One(body)
+    }
+}
+
+struct Entry {
+    payload: Payload,
+    rejected: u32,
+    // History ids of the samples it carries; empty for anything else
+    samples: Range<u32>,
+}
+
+/// Samples whose payloads left the queue, by how they left it.
+#[derive(Default)]
+pub struct Settled {
+    pub sent: Vec<Range<u32>>,
+    pub dropped: Vec<Range<u32>>,
+}
+
+/// Payloads waiting for the server's ack. The front entry is only popped on a
+/// 2xx, except when it keeps getting 4xx: after `max_rejects` it is treated as
+/// poison and dropped so it can't block the rest of the queue.
+pub struct Outbox {
+    queue: VecDeque<Entry>,
+    capacity: usize,
+    max_rejects: u32,
+    poisoned: u32,
+    settled: Settled,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, max_rejects: u32) -> Self {
+        Self {
+            queue: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            max_rejects: max_rejects.max(1),
+            poisoned: 0,
+            settled: Settled::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queued payloads, oldest first.
+    pub fn payloads(&self) -> impl DoubleEndedIterator<Item = &Payload> {
+        self.queue.iter().map(|e| &e.payload)
+    }
+
+    pub fn push(&mut self, payload: Payload, samples: Range<u32>) {
+        if self.queue.len() >= self.capacity {
+            if let Some(oldest) = self.queue.pop_front() {
+                settle(&mut self.settled.dropped, oldest.samples);
+            }
+            log::warn!("Outbox full, dropped oldest payload");
+        }
+        self.queue.push_back(Entry { payload, rejected: 0, samples });
+    }
+
+    /// Samples acked or dropped since the last call.
+    pub fn take_settled(&mut self) -> Settled {
+        std::mem::take(&mut self.settled)
+    }
+
+    /// Sends queued payloads in order until one fails; returns how many were acked.
+    pub fn flush(&mut self, mut send: impl FnMut(&Payload) -> Result<u16, AppError>) -> usize {
+        let mut delivered = 0;
+        while let Some(front) = self.queue.front_mut() {
+            match send(&front.payload) {
+                Ok(_) => {
+                    if let Some(sent) = self.queue.pop_front() {
+                        settle(&mut self.settled.sent, sent.samples);
+                    }
+                    delivered += 1;
+                }
+                Err(AppError::Http(status)) if (400..500).contains(&status) => {
+                    front.rejected += 1;
+                    if front.rejected < self.max_rejects {
+                        log::warn!("Payload rejected with {status} ({}/{})", front.rejected, self.max_rejects);
+                        break;
+                    }
+                    if let Some(poison) = self.queue.pop_front() {
+                        settle(&mut self.settled.dropped, poison.samples);
+                    }
+                    self.poisoned += 1;
+                    log::error!("Dropped poison payload after {status} ({} dropped so far)", self.poisoned);
+                }
+                Err(AppError::Http(status)) => {
+                    log::warn!("Server answered {status}, retrying later");
+                    break;
+                }
+                Err(AppError::Held(why)) => {
+                    log::debug!("Payload held back: {why}");
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("POST failed: {e}");
+                    break;
+                }
+            }
+        }
+        delivered
+    }
+}
+
+fn settle(ranges: &mut Vec<Range<u32>>, samples: Range<u32>) {
+    if !samples.is_empty() {
+        ranges.push(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(s: &str) -> Payload {
+        Payload::One(s.to_owned())
+    }
+
+    fn ids(ranges: &[Range<u32>]) -> Vec<u32> {
+        ranges.iter().cloned().flatten().collect()
+    }
+
+    #[test]
+    fn settles_acked_and_dropped_samples() {
+        let mut outbox = Outbox::new(2, 2);
+        outbox.push(body("a"), 0..2);
+        outbox.push(body("b"), 2..3);
+        // Full: the oldest payload and its samples go
+        outbox.push(body("c"), 3..5);
+        outbox.push(body("ping"), 0..0);
+        let settled = outbox.take_settled();
+        assert_eq!(ids(&settled.dropped), [0, 1, 2]);
+        assert!(settled.sent.is_empty());
+
+        // "c" is poison after two 4xx, "ping" carries no samples
+        let mut answers = vec![Err(AppError::Http(400)), Err(AppError::Http(400)), Ok(200)];
+        answers.reverse();
+        assert_eq!(outbox.flush(|_| answers.pop().unwrap()), 0);
+        assert_eq!(outbox.flush(|_| answers.pop().unwrap()), 1);
+        assert!(outbox.is_empty());
+        let settled = outbox.take_settled();
+        assert_eq!(ids(&settled.dropped), [3, 4]);
+        assert!(settled.sent.is_empty());
+    }
+
+    #[test]
+    fn settles_on_ack_only() {
+        let mut outbox = Outbox::new(4, 3);
+        outbox.push(body("a"), 0..1);
+        outbox.push(body("b"), 1..2);
+        assert_eq!(outbox.flush(|_| Err(AppError::Send("down".into()))), 0);
+        assert!(outbox.take_settled().sent.is_empty());
+        let mut answers = vec![Err(AppError::Http(503)), Ok(200)];
+        assert_eq!(outbox.flush(|_| answers.pop().unwrap()), 1);
+        assert_eq!(ids(&outbox.take_settled().sent), [0]);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::transport::Sink;
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Per sink; NVS strings top out around 4000 bytes
+const MAX_PERSISTED_BYTES: usize = 3000;
+
+static VERIFY_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Writes that still read back wrong after a retry, since boot.
+pub fn verify_failures() -> u32 {
+    VERIFY_FAILURES.load(Ordering::Relaxed)
+}
+
+fn reads_back(nvs: &EspNvs<NvsDefault>, key: &str, value: &str) -> bool {
+    let mut buf = vec![0u8; value.len() + 1];
+    matches!(nvs.get_str(key, &mut buf), Ok(Some(stored)) if stored == value)
+}
+
+/// Stores a critical string. With `NVS_VERIFY` on it is read back after the
+/// write and written once more on a mismatch; a second mismatch is an error.
+pub fn write_verified(nvs: &mut EspNvs<NvsDefault>, key: &str, value: &str) -> Result<()> {
+    nvs.set_str(key, value)?;
+    if !crate::NVS_VERIFY || reads_back(nvs, key, value) {
+        return Ok(());
+    }
+    log::warn!("NVS '{key}' read back wrong, writing it again");
+    nvs.set_str(key, value)?;
+    if reads_back(nvs, key, value) {
+        return Ok(());
+    }
+    VERIFY_FAILURES.fetch_add(1, Ordering::Relaxed);
+    log::error!("NVS '{key}' still reads back wrong, the flash may be failing");
+    Err(anyhow!("NVS '{key}' failed read-back verification"))
+}
+
+fn key(sink: &Sink) -> String {
+    // NVS keys are limited to 15 characters
+    format!("buf_{}", sink.name()).chars().take(15).collect()
+}
+
+/// Saves every sink's queue to NVS ahead of a planned reboot. Only the newest
+/// payloads that fit are kept, and empty queues write nothing.
+pub fn buffer_persist(nvs: &mut EspNvs<NvsDefault>, sinks: &[Sink]) {
+    for sink in sinks {
+        let mut kept = Vec::new();
+        let mut size = 2;
+        for payload in sink.payloads().rev() {
+            // Batches are saved joined and come back as a single body
+            let payload = payload.joined();
+            // Each payload ends up as an escaped JSON string plus a comma
+            size += serde_json::to_string(&payload).map_or(usize::MAX, |s| s.len()) + 1;
+            if size > MAX_PERSISTED_BYTES {
+                break;
+            }
+            kept.push(payload);
+        }
+        if kept.is_empty() {
+            continue;
+        }
+        kept.reverse();
+        let total = sink.payloads().count();
+        let json = serde_json::to_string(&kept).unwrap_or_default();
+        match nvs.set_str(&key(sink), &json) {
+            Ok(()) => log::info!("💾 {}: persisted {} of {total} queued payloads", sink.name(), kept.len()),
+            Err(e) => log::warn!("{}: cannot persist queue: {:?}", sink.name(), e),
+        }
+    }
+}
+
+/// Requeues what `buffer_persist` saved, then clears it so it is sent once.
+pub fn buffer_restore(nvs: &mut EspNvs<NvsDefault>, sinks: &mut [Sink]) {
+    for sink in sinks.iter_mut() {
+        let key = key(sink);
+        let mut buf = vec![0u8; MAX_PERSISTED_BYTES + 1];
+        let saved = match nvs.get_str(&key, &mut buf) {
+            Ok(Some(json)) => serde_json::from_str::<Vec<String>>(json).ok(),
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("{}: cannot read persisted queue: {:?}", sink.name(), e);
+                None
+            }
+        };
+        let count = saved.as_ref().map_or(0, Vec::len);
+        for payload in saved.into_iter().flatten() {
+            sink.push(payload);
+        }
+        log::info!("💾 {}: restored {count} payloads", sink.name());
+        if let Err(e) = nvs.remove(&key) {
+            log::warn!("{}: cannot clear persisted queue: {:?}", sink.name(), e);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use esp_idf_hal::gpio::{AnyIOPin, AnyOutputPin, Output, PinDriver, Pull};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A press shorter than this is treated as a bounce or a bump
+const HOLD: Duration = Duration::from_secs(1);
+const POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// Long press accepted during the cooldown; a confirm press is awaited
+    Armed,
+    /// The confirm window ran out
+    Disarmed,
+    Reprovision,
+}
+
+/// Decides when the button may reopen the setup AP. Within `cooldown` of the
+/// last provisioning a plain press is not enough: it takes a long press to
+/// arm, then a second press within `confirm_window`.
+pub struct Guard {
+    // None when this boot joined with stored credentials: no cooldown then
+    provisioned_at: Option<Instant>,
+    cooldown: Duration,
+    long_press: Duration,
+    confirm_window: Duration,
+    pressed_since: Option<Instant>,
+    // Set while a press has already produced its action
+    press_used: bool,
+    armed_until: Option<Instant>,
+}
+
+impl Guard {
+    pub fn new(
+        provisioned_at: Option<Instant>,
+        cooldown: Duration,
+        long_press: Duration,
+        confirm_window: Duration,
+    ) -> Self {
+        Self {
+            provisioned_at,
+            cooldown,
+            long_press,
+            confirm_window,
+            pressed_since: None,
+            press_used: false,
+            armed_until: None,
+        }
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed_until.is_some()
+    }
+
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Action {
+        if self.armed_until.is_some_and(|until| now >= until) {
+            self.armed_until = None;
+            return Action::Disarmed;
+        }
+        if !pressed {
+            self.pressed_since = None;
+            self.press_used = false;
+            return Action::None;
+        }
+        let since = *self.pressed_since.get_or_insert(now);
+        if self.press_used {
+            return Action::None;
+        }
+        let held = now.duration_since(since);
+        let in_cooldown =
+            self.provisioned_at.is_some_and(|at| now.duration_since(at) < self.cooldown);
+
+        if self.armed_until.is_some() || !in_cooldown {
+            if held >= HOLD {
+                self.press_used = true;
+                self.armed_until = None;
+                return Action::Reprovision;
+            }
+        } else if held >= self.long_press {
+            self.press_used = true;
+            self.armed_until = Some(now + self.confirm_window);
+            return Action::Armed;
+        }
+        Action::None
+    }
+}
+
+/// Watches the (active-low) button on its own thread and calls `reprovision`
+/// when the guard allows it. The LED, if any, blinks while armed.
+pub fn spawn_watch(
+    button: AnyIOPin,
+    led: Option<AnyOutputPin>,
+    mut guard: Guard,
+    mut reprovision: impl FnMut() + Send + 'static,
+) -> Result<()> {
+    let mut button = PinDriver::input(button)?;
+    button.set_pull(Pull::Up)?;
+    let mut led: Option<PinDriver<'static, AnyOutputPin, Output>> = match led {
+        Some(pin) => PinDriver::output(pin)
+            .map_err(|e| log::warn!("Status LED unavailable: {:?}", e))
+            .ok(),
+        None => None,
+    };
+
+    thread::Builder::new()
+        .name("reprovision".into())
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            let mut blink = false;
+            loop {
+                match guard.update(button.is_low(), Instant::now()) {
+                    Action::None => {}
+                    Action::Armed => log::info!("🔓 Re-provisioning armed, press again to confirm"),
+                    Action::Disarmed => log::info!("🔒 Re-provisioning disarmed"),
+                    Action::Reprovision => {
+                        log::info!("🔁 Re-provisioning requested");
+                        reprovision();
+                    }
+                }
+                if let Some(led) = led.as_mut() {
+                    blink = guard.is_armed() && !blink;
+                    let _ = led.set_level(blink.into());
+                }
+                thread::sleep(POLL);
+            }
+        })
+        .context("Button thread")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(600);
+    const LONG_PRESS: Duration = Duration::from_secs(5);
+    const CONFIRM: Duration = Duration::from_secs(10);
+
+    fn guard(start: Instant) -> Guard {
+        Guard::new(Some(start), COOLDOWN, LONG_PRESS, CONFIRM)
+    }
+
+    /// Holds the button from `from` for `held`, polling every `POLL`, and
+    /// returns every action that was not `None`.
+    fn hold(guard: &mut Guard, from: Instant, held: Duration) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let mut at = from;
+        while at <= from + held {
+            match guard.update(true, at) {
+                Action::None => {}
+                action => actions.push(action),
+            }
+            at += POLL;
+        }
+        assert_eq!(guard.update(false, at), Action::None);
+        actions
+    }
+
+    #[test]
+    fn plain_press_after_cooldown() {
+        let start = Instant::now();
+        let mut guard = guard(start);
+        let after = start + COOLDOWN;
+        assert!(hold(&mut guard, after, HOLD / 2).is_empty());
+        assert_eq!(hold(&mut guard, after + HOLD, HOLD), [Action::Reprovision]);
+    }
+
+    #[test]
+    fn no_cooldown_without_provisioning() {
+        let start = Instant::now();
+        let mut guard = Guard::new(None, COOLDOWN, LONG_PRESS, CONFIRM);
+        assert_eq!(hold(&mut guard, start, HOLD), [Action::Reprovision]);
+    }
+
+    #[test]
+    fn long_press_arms_and_confirm_fires() {
+        let start = Instant::now();
+        let mut guard = guard(start);
+        // A plain press is not enough within the cooldown
+        assert!(hold(&mut guard, start, HOLD * 2).is_empty());
+        assert_eq!(hold(&mut guard, start + LONG_PRESS, LONG_PRESS), [Action::Armed]);
+        assert!(guard.is_armed());
+        let confirm = start + LONG_PRESS * 2 + Duration::from_secs(2);
+        assert_eq!(hold(&mut guard, confirm, HOLD), [Action::Reprovision]);
+        assert!(!guard.is_armed());
+    }
+
+    #[test]
+    fn confirm_window_expires() {
+        let start = Instant::now();
+        let mut guard = guard(start);
+        assert_eq!(hold(&mut guard, start, LONG_PRESS), [Action::Armed]);
+        let armed_at = start + LONG_PRESS;
+        assert_eq!(guard.update(false, armed_at + CONFIRM), Action::Disarmed);
+        assert!(!guard.is_armed());
+        // Back to needing a long press
+        assert!(hold(&mut guard, armed_at + CONFIRM, HOLD * 2).is_empty());
+    }
+
+    #[test]
+    fn held_press_fires_once() {
+        let start = Instant::now();
+        let mut guard = guard(start);
+        // Armed by the long press, which keeps going well past the hold time
+        assert_eq!(hold(&mut guard, start, LONG_PRESS * 2), [Action::Armed]);
+
+        let mut guard = Guard::new(None, COOLDOWN, LONG_PRESS, CONFIRM);
+        assert_eq!(hold(&mut guard, start, HOLD * 10), [Action::Reprovision]);
+    }
+}
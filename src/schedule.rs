@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+struct Rule {
+    // Minutes since local midnight, end exclusive; start > end wraps past midnight
+    start: u16,
+    end: u16,
+    interval: Duration,
+}
+
+impl Rule {
+    fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Sampling intervals by time of day, e.g. `06:00-22:00=60,22:00-06:00=900`
+/// (seconds). The first matching rule wins.
+#[derive(Default)]
+pub struct Schedule {
+    rules: Vec<Rule>,
+}
+
+fn parse_hhmm(s: &str) -> Option<u16> {
+    let (h, m) = s.trim().split_once(':')?;
+    let (h, m): (u16, u16) = (h.parse().ok()?, m.parse().ok()?);
+    // 24:00 is allowed as an end of day
+    (h < 24 && m < 60 || h == 24 && m == 0).then_some(h * 60 + m)
+}
+
+impl Schedule {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let bad = || format!("Bad schedule rule '{item}'");
+            let (range, secs) = item.split_once('=').ok_or_else(bad)?;
+            let (start, end) = range.split_once('-').ok_or_else(bad)?;
+            let start = parse_hhmm(start).ok_or_else(bad)? % MINUTES_PER_DAY;
+            let end = parse_hhmm(end).ok_or_else(bad)?;
+            let secs: u64 = secs.trim().parse().map_err(|_| bad())?;
+            if secs == 0 {
+                return Err(bad());
+            }
+            rules.push(Rule { start, end, interval: Duration::from_secs(secs) });
+        }
+        Ok(Self { rules })
+    }
+
+    /// The longest interval any rule, or `base`, can give.
+    pub fn longest(&self, base: Duration) -> Duration {
+        self.rules.iter().map(|r| r.interval).fold(base, Duration::max)
+    }
+
+    /// Interval for the given local minute of day; `base` when the clock is
+    /// unknown or no rule matches.
+    pub fn interval_at(&self, minute_of_day: Option<u16>, base: Duration) -> Duration {
+        let Some(minute) = minute_of_day else {
+            return base;
+        };
+        self.rules
+            .iter()
+            .find(|r| r.contains(minute % MINUTES_PER_DAY))
+            .map_or(base, |r| r.interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_secs(300);
+
+    fn at(schedule: &Schedule, h: u16, m: u16) -> u64 {
+        schedule.interval_at(Some(h * 60 + m), BASE).as_secs()
+    }
+
+    #[test]
+    fn wraps_past_midnight() {
+        let schedule = Schedule::parse("22:00-06:00=900").unwrap();
+        assert_eq!(at(&schedule, 21, 59), 300);
+        assert_eq!(at(&schedule, 22, 0), 900);
+        assert_eq!(at(&schedule, 23, 59), 900);
+        assert_eq!(at(&schedule, 0, 0), 900);
+        assert_eq!(at(&schedule, 5, 59), 900);
+        assert_eq!(at(&schedule, 6, 0), 300);
+    }
+
+    #[test]
+    fn day_and_night_rules() {
+        let schedule = Schedule::parse("06:00-22:00=60, 22:00-06:00=900").unwrap();
+        assert_eq!(at(&schedule, 5, 59), 900);
+        assert_eq!(at(&schedule, 6, 0), 60);
+        assert_eq!(at(&schedule, 21, 59), 60);
+        assert_eq!(at(&schedule, 22, 0), 900);
+        assert_eq!(schedule.interval_at(None, BASE), BASE);
+    }
+
+    #[test]
+    fn end_of_day() {
+        let schedule = Schedule::parse("18:00-24:00=120").unwrap();
+        assert_eq!(at(&schedule, 17, 59), 300);
+        assert_eq!(at(&schedule, 23, 59), 120);
+        assert_eq!(at(&schedule, 0, 0), 300);
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let bad = ["22:00=60", "22:00-06:00", "25:00-06:00=60", "06:00-07:60=60", "06:00-07:00=0"];
+        for spec in bad {
+            assert!(Schedule::parse(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn longest_interval() {
+        let schedule = Schedule::parse("06:00-22:00=60, 22:00-06:00=900").unwrap();
+        assert_eq!(schedule.longest(BASE), Duration::from_secs(900));
+        assert_eq!(Schedule::default().longest(BASE), BASE);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::clock::{self, Clock};
+use crate::error::AppError;
+use crate::outbox::Payload;
+use crate::transport::Transport;
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::{AnyIOPin, InputPin, OutputPin};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::sd::{spi::SdSpiHostDriver, SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::{config::DriverConfig, Dma, SpiAnyPins, SpiDriver};
+use esp_idf_svc::io::vfs::MountedFatfs;
+use esp_idf_sys::EspError;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+const MOUNT_POINT: &str = "/sd";
+// Only one file is ever open, for the length of an append
+const MAX_OPEN_FILES: usize = 2;
+// newlib's errno for a full card
+const ENOSPC: i32 = 28;
+
+type Card = SdCardDriver<SdSpiHostDriver<'static, SpiDriver<'static>>>;
+
+/// Appends every payload to the SD card as newline-delimited JSON, one file
+/// per local day (`/sd/YYYYMMDD.JSN`, 8.3 names as FAT has them), so readings
+/// keep landing on the card while the network is down. Readings taken before
+/// the clock is set go to `UNDATED.JSN`.
+pub struct SdTransport {
+    clock: Arc<Mutex<Clock>>,
+    // Unmounts the card when dropped
+    _fatfs: MountedFatfs<Fatfs<Card>>,
+    // File of the last append, to log the daily switch once
+    current: Option<String>,
+    gave_up: bool,
+}
+
+impl SdTransport {
+    /// Fails when no card answers; a card that is there but unformatted or
+    /// full only shows up on the first write.
+    pub fn mount<S: SpiAnyPins>(
+        spi: impl Peripheral<P = S> + 'static,
+        sclk: impl Peripheral<P = impl OutputPin> + 'static,
+        mosi: impl Peripheral<P = impl OutputPin> + 'static,
+        miso: impl Peripheral<P = impl InputPin> + 'static,
+        cs: impl Peripheral<P = impl OutputPin> + 'static,
+        clock: Arc<Mutex<Clock>>,
+    ) -> Result<Self, EspError> {
+        let config = DriverConfig::default().dma(Dma::Auto(4096));
+        let spi = SpiDriver::new(spi, sclk, mosi, Some(miso), &config)?;
+        let host = SdSpiHostDriver::new(
+            spi,
+            Some(cs),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            #[cfg(not(any(
+                esp_idf_version_major = "4",
+                all(esp_idf_version_major = "5", esp_idf_version_minor = "0"),
+                all(esp_idf_version_major = "5", esp_idf_version_minor = "1"),
+            )))]
+            None,
+        )?;
+        let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+        let fatfs = MountedFatfs::mount(Fatfs::new_sdcard(0, card)?, MOUNT_POINT, MAX_OPEN_FILES)?;
+        Ok(Self { clock, _fatfs: fatfs, current: None, gave_up: false })
+    }
+
+    fn path(&self) -> String {
+        let Some(unix_ms) = self.clock.lock().unwrap().now().unix_ms else {
+            return format!("{MOUNT_POINT}/UNDATED.JSN");
+        };
+        let local_secs = (unix_ms / 1000) as i64 + crate::TZ_OFFSET_MINUTES * 60;
+        let (year, month, day) = clock::civil_date(local_secs.div_euclid(86_400));
+        format!("{MOUNT_POINT}/{year:04}{month:02}{day:02}.JSN")
+    }
+
+    fn append(&mut self, docs: &[&str]) -> Result<u16, AppError> {
+        if self.gave_up {
+            return Err(AppError::Send("SD logging stopped".into()));
+        }
+        let mut lines = String::new();
+        for doc in docs {
+            lines.push_str(doc);
+            lines.push('\n');
+        }
+        let path = self.path();
+        // Opened and closed per append, so a power cut loses at most this one
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()));
+        if let Err(e) = written {
+            // A pulled or full card isn't coming back by itself: stop instead of
+            // retrying every cycle
+            self.gave_up = true;
+            match e.raw_os_error() {
+                Some(ENOSPC) => log::error!("💾 SD card full, SD logging stopped"),
+                _ => log::error!("💾 Cannot write {path} ({e}), SD logging stopped"),
+            }
+            return Err(AppError::send(e));
+        }
+        if self.current.as_deref() != Some(path.as_str()) {
+            log::info!("💾 Logging to {path}");
+            self.current = Some(path);
+        }
+        Ok(200)
+    }
+}
+
+impl Transport for SdTransport {
+    fn name(&self) -> &str {
+        "sd"
+    }
+
+    fn send(&mut self, body: &[u8]) -> Result<u16, AppError> {
+        let body = std::str::from_utf8(body).map_err(AppError::send)?;
+        self.append(&[body])
+    }
+
+    /// Batches go down one reading per line rather than as one array.
+    fn send_payload(&mut self, payload: &Payload) -> Result<u16, AppError> {
+        match payload {
+            Payload::One(body) => self.append(&[body]),
+            Payload::Array(items) => {
+                let items: Vec<&str> = items.iter().map(String::as_str).collect();
+                self.append(&items)
+            }
+        }
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn gave_up(&self) -> bool {
+        self.gave_up
+    }
+}
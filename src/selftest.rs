@@ -0,0 +1,106 @@
+use crate::sensor::{plausible, Sensor, QUALITY_NO_SENSOR};
+use crate::transport::Transport;
+use std::net::{Ipv4Addr, ToSocketAddrs};
+use std::time::Instant;
+
+/// Result of one bring-up check, with what it found.
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// Does not apply to this setup, e.g. a POST against an MQTT broker
+    Skip(String),
+}
+
+/// Bring-up checks, in the order they were run.
+#[derive(Default)]
+pub struct Report {
+    checks: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+    pub fn record(&mut self, name: &'static str, outcome: Outcome) {
+        self.checks.push((name, outcome));
+    }
+
+    pub fn passed(&self) -> bool {
+        !self.checks.iter().any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+    }
+
+    /// One line per check, then the verdict; failures at error level.
+    pub fn log(&self) {
+        log::info!("🧪 Self-test:");
+        for (name, outcome) in &self.checks {
+            match outcome {
+                Outcome::Pass(detail) => log::info!("🧪   PASS {name:<7} {detail}"),
+                Outcome::Fail(detail) => log::error!("🧪   FAIL {name:<7} {detail}"),
+                Outcome::Skip(detail) => log::info!("🧪   SKIP {name:<7} {detail}"),
+            }
+        }
+        let failed = self.checks.iter().filter(|(_, o)| matches!(o, Outcome::Fail(_))).count();
+        if failed == 0 {
+            log::info!("🧪 Self-test PASS");
+        } else {
+            log::error!("🧪 Self-test FAIL ({failed} of {} checks)", self.checks.len());
+        }
+    }
+}
+
+/// One read from every sensor, each one `plausible` as for sending.
+pub fn check_sensors(sensors: &mut [Box<dyn Sensor + Send>]) -> Outcome {
+    if sensors.is_empty() {
+        return Outcome::Fail("no sensor configured".into());
+    }
+    let mut seen = Vec::new();
+    for sensor in sensors.iter_mut() {
+        let name = sensor.label().unwrap_or("sensor").to_owned();
+        let readings = match sensor.read() {
+            Ok(readings) => readings,
+            Err(e) => return Outcome::Fail(format!("{name}: {e:#}")),
+        };
+        for m in readings {
+            if m.quality & QUALITY_NO_SENSOR != 0 {
+                return Outcome::Fail(format!("{name}: no sensor answered"));
+            }
+            let shown = match m.humidity {
+                Some(h) => format!("{:.1} °C {h:.0} %", m.temperature),
+                None => format!("{:.1} °C", m.temperature),
+            };
+            if !plausible(&m, &crate::PLAUSIBLE_TEMP) {
+                return Outcome::Fail(format!("{name}: {shown} is implausible"));
+            }
+            seen.push(shown);
+        }
+    }
+    Outcome::Pass(seen.join(", "))
+}
+
+/// The host of `url` resolves; an address literal needs no lookup.
+pub fn check_dns(url: &str) -> Outcome {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = authority.split(':').next().unwrap_or_default();
+    if host.is_empty() {
+        return Outcome::Fail(format!("no host in {url}"));
+    }
+    if host.parse::<Ipv4Addr>().is_ok() {
+        return Outcome::Skip(format!("{host} is an address"));
+    }
+    let started = Instant::now();
+    match (host, 0).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => {
+            Outcome::Pass(format!("{host} → {} in {} ms", addr.ip(), started.elapsed().as_millis()))
+        }
+        Ok(None) => Outcome::Fail(format!("{host} has no address")),
+        Err(e) => Outcome::Fail(format!("{host}: {e}")),
+    }
+}
+
+/// A single send of `body` gets a 2xx.
+pub fn check_post(transport: &mut dyn Transport, body: &str) -> Outcome {
+    let started = Instant::now();
+    match transport.send(body.as_bytes()) {
+        Ok(status) => Outcome::Pass(format!("{status} in {} ms", started.elapsed().as_millis())),
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
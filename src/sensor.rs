@@ -0,0 +1,393 @@
+use crate::aggregate::Aggregator;
+use crate::dht::{self, Dht11Reading, Frame, FrameError, SensorKind};
+use anyhow::{anyhow, Result};
+use esp_idf_hal::delay::Ets;
+use esp_idf_hal::gpio::{AnyIOPin, InputOutput, PinDriver, Pull};
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type IoPin<'d> = PinDriver<'d, AnyIOPin, InputOutput>;
+
+// Quality bits reported alongside a measurement
+pub const QUALITY_SLOW_READ: u8 = 1 << 0;
+pub const QUALITY_CORRECTED: u8 = 1 << 1;
+// Placeholder sent when no sensor answered
+pub const QUALITY_NO_SENSOR: u8 = 1 << 2;
+// Sent anyway although `plausible` rejected it
+pub const QUALITY_IMPLAUSIBLE: u8 = 1 << 3;
+// Line timeouts in a row after which the wiring is blamed out loud
+const TIMEOUT_STREAK: u32 = 5;
+
+#[derive(Clone, Default)]
+pub struct Measurement {
+    // Set when several readings come from one backend, e.g. a 1-Wire ROM id
+    pub tag: Option<String>,
+    pub temperature: f32,
+    // None for temperature-only sensors
+    pub humidity: Option<f32>,
+    pub read_duration_us: Option<u32>,
+    pub quality: u8,
+}
+
+/// Failed frames since boot, by cause. Timeouts point at wiring or the
+/// pull-up, checksum mismatches at a noisy or overlong cable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadErrors {
+    pub timeouts: u32,
+    pub checksum_mismatches: u32,
+    /// Good frames whose values `plausible` rejected
+    pub implausible: u32,
+}
+
+impl ReadErrors {
+    pub fn add(self, other: ReadErrors) -> ReadErrors {
+        ReadErrors {
+            timeouts: self.timeouts + other.timeouts,
+            checksum_mismatches: self.checksum_mismatches + other.checksum_mismatches,
+            implausible: self.implausible + other.implausible,
+        }
+    }
+}
+
+/// Whether a reading that passed its checksum can be real: temperature in
+/// `temperature`, humidity 0–100 %, and not the all-zero frame a DHT11 gives
+/// when it answers without measuring.
+pub fn plausible(m: &Measurement, temperature: &RangeInclusive<f32>) -> bool {
+    let humidity_ok = match m.humidity {
+        Some(h) => (0.0..=100.0).contains(&h),
+        None => true,
+    };
+    let all_zero = m.temperature == 0.0 && m.humidity == Some(0.0);
+    temperature.contains(&m.temperature) && humidity_ok && !all_zero
+}
+
+pub trait Sensor {
+    fn read(&mut self) -> Result<Vec<Measurement>>;
+
+    /// Name its readings are sent under, for a sensor that is one of several.
+    fn label(&self) -> Option<&str> {
+        None
+    }
+
+    /// Frame errors counted so far, for sensors that tell them apart.
+    fn read_errors(&self) -> ReadErrors {
+        ReadErrors::default()
+    }
+}
+
+pub struct DhtSensor<'d> {
+    pin: IoPin<'d>,
+    kind: SensorKind,
+    // Reads slower than this get QUALITY_SLOW_READ
+    slow_read_us: u32,
+    slow_reads: u32,
+    // Opt-in repair of single-bit checksum failures, DHT11 only
+    checksum_correction: bool,
+    last_good: Option<Dht11Reading>,
+    attempts: u32,
+    retry_spacing: Duration,
+    label: Option<String>,
+    errors: ReadErrors,
+    timeouts_in_row: u32,
+}
+
+impl<'d> DhtSensor<'d> {
+    /// A read is tried up to `attempts` times, `retry_spacing` apart.
+    pub fn new(
+        pin: AnyIOPin,
+        kind: SensorKind,
+        slow_read_us: u32,
+        checksum_correction: bool,
+        attempts: u32,
+        retry_spacing: Duration,
+    ) -> Result<Self> {
+        let mut pin = PinDriver::input_output_od(pin)?;
+        pin.set_pull(Pull::Up)?;
+        Ok(Self {
+            pin,
+            kind,
+            slow_read_us,
+            slow_reads: 0,
+            checksum_correction: checksum_correction && kind == SensorKind::Dht11,
+            last_good: None,
+            attempts: attempts.max(1),
+            retry_spacing,
+            label: None,
+            errors: ReadErrors::default(),
+            timeouts_in_row: 0,
+        })
+    }
+
+    /// Tags every reading with `label`, so several DHTs can be told apart.
+    pub fn labeled(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    fn wait_level(&self, high: bool) -> Result<(), FrameError> {
+        for _ in 0..100 {
+            if self.pin.is_high() == high {
+                return Ok(());
+            }
+            Ets::delay_us(1);
+        }
+        Err(FrameError::Timeout)
+    }
+
+    /// Bit-bangs one 40-bit frame; the checksum is left to the caller.
+    fn read_frame(&mut self) -> Result<Frame> {
+        self.pin.set_low()?;
+        Ets::delay_ms(self.kind.start_ms());
+        self.pin.set_high()?;
+        Ets::delay_us(48);
+
+        self.wait_level(true)?;
+        self.wait_level(false)?;
+        let mut frame = [0u8; 5];
+        for byte in frame.iter_mut() {
+            for _ in 0..8 {
+                self.wait_level(true)?;
+                // A 1 stays high ~70 µs, a 0 ~27 µs
+                Ets::delay_us(35);
+                let bit = self.pin.is_high();
+                self.wait_level(false)?;
+                *byte = (*byte << 1) | bit as u8;
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Counts a failed attempt by its cause. Anything but a frame error,
+    /// like a GPIO failure, is only logged.
+    fn count_error(&mut self, e: &anyhow::Error) {
+        match e.downcast_ref::<FrameError>() {
+            Some(FrameError::Timeout) => {
+                self.errors.timeouts += 1;
+                self.timeouts_in_row += 1;
+                if self.timeouts_in_row % TIMEOUT_STREAK == 0 {
+                    log::error!(
+                        "{} timed out {} times in a row, check the wiring and the pull-up",
+                        self.kind.name(),
+                        self.timeouts_in_row
+                    );
+                }
+            }
+            Some(FrameError::ChecksumMismatch) => {
+                self.errors.checksum_mismatches += 1;
+                // The sensor answered, so the line itself is wired
+                self.timeouts_in_row = 0;
+            }
+            None => {}
+        }
+    }
+
+    fn read_once(&mut self) -> Result<Measurement> {
+        let name = self.kind.name();
+        let started = Instant::now();
+        let frame = self.read_frame();
+        let read_duration_us = started.elapsed().as_micros() as u32;
+
+        let frame =
+            frame.map_err(|e| e.context(format!("{name} read error after {read_duration_us} µs")))?;
+        let mut quality = 0;
+        let frame = if dht::checksum_ok(&frame) {
+            frame
+        } else {
+            let corrected = self
+                .checksum_correction
+                .then(|| dht::correct_single_bit(&frame, self.last_good))
+                .flatten();
+            let Some(corrected) = corrected else {
+                let e = anyhow::Error::new(FrameError::ChecksumMismatch);
+                return Err(e.context(format!("{name} read error")));
+            };
+            log::warn!("DHT11 checksum repaired: {:02X?} → {:02X?}", frame, corrected);
+            quality |= QUALITY_CORRECTED;
+            corrected
+        };
+        if self.kind == SensorKind::Dht11 && quality & QUALITY_CORRECTED == 0 {
+            self.last_good = Some(dht::decode(&frame));
+        }
+        let (temperature, humidity) = dht::decode_as(self.kind, &frame);
+
+        if read_duration_us > self.slow_read_us {
+            quality |= QUALITY_SLOW_READ;
+            self.slow_reads += 1;
+            log::warn!(
+                "Slow {name} read: {read_duration_us} µs ({} so far), check the wiring",
+                self.slow_reads
+            );
+        }
+        Ok(Measurement {
+            temperature,
+            humidity: Some(humidity),
+            tag: self.label.clone(),
+            read_duration_us: Some(read_duration_us),
+            quality,
+            ..Default::default()
+        })
+    }
+}
+
+impl Sensor for DhtSensor<'_> {
+    fn read(&mut self) -> Result<Vec<Measurement>> {
+        let mut attempt = 1;
+        loop {
+            let read = self.read_once();
+            if let Err(e) = &read {
+                self.count_error(e);
+            }
+            match read {
+                Ok(m) => {
+                    self.timeouts_in_row = 0;
+                    return Ok(vec![m]);
+                }
+                Err(e) if attempt < self.attempts => {
+                    log::warn!("{e:#}, retrying ({attempt}/{})", self.attempts);
+                    thread::sleep(self.retry_spacing);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    fn read_errors(&self) -> ReadErrors {
+        self.errors
+    }
+}
+
+/// Reads another sensor `window` times, `spacing` apart, and reports the mean
+/// per tag. Failed reads are left out of the mean; only a window without a
+/// single good read fails.
+pub struct Averaged<S> {
+    inner: S,
+    window: u32,
+    spacing: Duration,
+}
+
+impl<S: Sensor> Averaged<S> {
+    pub fn new(inner: S, window: u32, spacing: Duration) -> Self {
+        Self { inner, window: window.max(1), spacing }
+    }
+}
+
+impl<S: Sensor> Sensor for Averaged<S> {
+    fn read(&mut self) -> Result<Vec<Measurement>> {
+        let mut aggregator = Aggregator::default();
+        let mut quality = 0;
+        let mut failed = 0;
+        for i in 0..self.window {
+            if i > 0 {
+                thread::sleep(self.spacing);
+            }
+            match self.inner.read() {
+                Ok(readings) => {
+                    for m in readings {
+                        aggregator.push(m.tag.as_deref(), m.temperature, m.humidity);
+                        quality |= m.quality;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("{e:#}, left out of the average");
+                    failed += 1;
+                }
+            }
+        }
+        if aggregator.is_empty() {
+            return Err(anyhow!("No good read in a window of {}", self.window));
+        }
+        if failed > 0 {
+            let good = self.window - failed;
+            log::info!(target: crate::CYCLE_LOG, "Averaged {good} of {} reads", self.window);
+        }
+        Ok(aggregator
+            .take()
+            .into_iter()
+            .map(|s| Measurement {
+                tag: s.tag,
+                temperature: s.temperature,
+                humidity: s.humidity,
+                quality,
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.inner.label()
+    }
+
+    fn read_errors(&self) -> ReadErrors {
+        self.inner.read_errors()
+    }
+}
+
+#[cfg(feature = "ds18b20")]
+pub use self::ds18b20_bus::Ds18b20Bus;
+
+#[cfg(feature = "ds18b20")]
+mod ds18b20_bus {
+    use super::{IoPin, Measurement, Sensor};
+    use anyhow::{anyhow, Result};
+    use ds18b20::{Ds18b20, Resolution};
+    use esp_idf_hal::delay::{Ets, FreeRtos};
+    use esp_idf_hal::gpio::{AnyIOPin, PinDriver, Pull};
+    use esp_idf_hal::sys::EspError;
+    use one_wire_bus::{Address, OneWire};
+
+    /// Every DS18B20 found on one 1-Wire bus, reported by ROM id.
+    pub struct Ds18b20Bus<'d> {
+        bus: OneWire<IoPin<'d>>,
+        delay: Ets,
+    }
+
+    impl<'d> Ds18b20Bus<'d> {
+        pub fn new(pin: AnyIOPin) -> Result<Self> {
+            let mut pin = PinDriver::input_output_od(pin)?;
+            pin.set_pull(Pull::Up)?;
+            let bus = OneWire::new(pin).map_err(|e| anyhow!("1-Wire init: {:?}", e))?;
+            Ok(Self { bus, delay: Ets })
+        }
+
+        fn probes(&mut self) -> Vec<Address> {
+            self.bus
+                .devices(false, &mut self.delay)
+                .filter_map(|r| r.ok())
+                .filter(|a| a.family_code() == ds18b20::FAMILY_CODE)
+                .collect()
+        }
+    }
+
+    impl Sensor for Ds18b20Bus<'_> {
+        fn read(&mut self) -> Result<Vec<Measurement>> {
+            ds18b20::start_simultaneous_temp_measurement(&mut self.bus, &mut self.delay)
+                .map_err(|e| anyhow!("DS18B20 convert: {:?}", e))?;
+            Resolution::Bits12.delay_for_measurement_time(&mut FreeRtos);
+
+            let mut out = Vec::new();
+            for address in self.probes() {
+                let tag = format!("{:016X}", address.0);
+                let data = Ds18b20::new::<EspError>(address)
+                    .and_then(|probe| probe.read_data(&mut self.bus, &mut self.delay));
+                match data {
+                    Ok(data) => out.push(Measurement {
+                        tag: Some(tag),
+                        temperature: data.temperature,
+                        ..Default::default()
+                    }),
+                    Err(e) => log::warn!("DS18B20 {tag} read error: {:?}", e),
+                }
+            }
+            if out.is_empty() {
+                return Err(anyhow!("No DS18B20 probe answered"));
+            }
+            Ok(out)
+        }
+    }
+}
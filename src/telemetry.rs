@@ -0,0 +1,354 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+pub const FIELDS: &[&str] = &[
+    "ping",
+    "device_id",
+    "temperature",
+    "humidity",
+    "sensor",
+    "ts",
+    "uptime_ms",
+    "time_source",
+    "read_duration_us",
+    "quality",
+    "dropped",
+    "battery_pct",
+    "samples",
+    "sensor_error",
+    "auth_mode",
+    "unit",
+    "boot_count",
+    "reset_reason",
+    "dht_timeouts",
+    "dht_checksum_errors",
+    "implausible_readings",
+    "analog_mv",
+    "analog",
+    "read_ms",
+    "post_ms",
+    "success_pct",
+    "wifi_reconnects",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TempUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl TempUnit {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "C" => Some(Self::Celsius),
+            "F" => Some(Self::Fahrenheit),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Fahrenheit => "F",
+        }
+    }
+
+    /// `celsius` in this unit, on the float as read so nothing is rounded.
+    pub fn convert(self, celsius: f32) -> f32 {
+        match self {
+            Self::Celsius => celsius,
+            Self::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+}
+
+/// How the HTTP sink puts a payload on the wire. Payloads are built and
+/// queued as JSON either way; CBOR is encoded from it at send time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "json" => Some(Self::Json),
+            "cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Telemetry<'a> {
+    pub ping: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<&'a str>,
+    // Left out, like humidity, when no sensor answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_ms: Option<u64>,
+    pub time_source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_duration_us: Option<u32>,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub quality: u8,
+    // Samples lost to backpressure since boot
+    #[serde(skip_serializing_if = "is_zero")]
+    pub dropped: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_pct: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples: Option<u32>,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub sensor_error: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_mode: Option<&'static str>,
+    // Unit of `temperature`, "C" or "F"
+    pub unit: &'static str,
+    // Lifetime boots and why the last one happened, for field reboots
+    pub boot_count: u32,
+    pub reset_reason: &'static str,
+    // Failed DHT frames since boot, by cause
+    #[serde(skip_serializing_if = "is_zero")]
+    pub dht_timeouts: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub dht_checksum_errors: u32,
+    // Good frames with impossible values, since boot
+    #[serde(skip_serializing_if = "is_zero")]
+    pub implausible_readings: u32,
+    // Analog input: pin voltage and its calibrated value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analog_mv: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analog: Option<f32>,
+    // Self-report with REPORT_METRICS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_pct: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wifi_reconnects: Option<u32>,
+}
+
+fn is_zero<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+
+// Left out of the JSON when zero; a template gets the zero instead of `null`
+const ZERO_SKIPPED: &[(&str, &str)] = &[
+    ("quality", "0"),
+    ("dropped", "0"),
+    ("sensor_error", "false"),
+    ("dht_timeouts", "0"),
+    ("dht_checksum_errors", "0"),
+    ("implausible_readings", "0"),
+];
+
+/// A payload shape with `{{field}}` placeholders, e.g.
+/// `{"temp":{{temperature}},"id":{{device_id}}}`. Each is replaced by the
+/// field's JSON value, strings quoted, or `null` when the payload leaves it out.
+/// Counters and flags the JSON skips at zero render as `0` or `false`.
+pub struct Template {
+    text: String,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or_else(|| "unclosed '{{' in template".to_owned())?;
+            let name = after[..end].trim();
+            if !FIELDS.contains(&name) {
+                return Err(format!("unknown template field '{name}'"));
+            }
+            rest = &after[end + 2..];
+        }
+        Ok(Self { text: text.to_owned() })
+    }
+
+    fn render(&self, fields: &Value) -> String {
+        let mut out = String::with_capacity(self.text.len() + 32);
+        let mut rest = self.text.as_str();
+        // Placeholders were checked by `parse`
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after.find("}}").unwrap_or(after.len());
+            let name = after[..end].trim();
+            match fields.get(name) {
+                Some(value) => out.push_str(&value.to_string()),
+                None => match ZERO_SKIPPED.iter().find(|&&(field, _)| field == name) {
+                    Some((_, zero)) => out.push_str(zero),
+                    None => out.push_str("null"),
+                },
+            }
+            rest = after.get(end + 2..).unwrap_or("");
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Payload fields to keep; all of them when built from an empty list.
+pub struct FieldFilter {
+    enabled: Option<Vec<&'static str>>,
+    // Replaces the JSON object altogether when set
+    template: Option<Template>,
+}
+
+impl FieldFilter {
+    pub fn new(fields: &[&'static str]) -> Result<Self> {
+        if fields.is_empty() {
+            return Ok(Self { enabled: None, template: None });
+        }
+        if let Some(unknown) = fields.iter().find(|f| !FIELDS.contains(f)) {
+            return Err(anyhow!("Unknown telemetry field '{unknown}'"));
+        }
+        if !fields.contains(&"temperature") && !fields.contains(&"humidity") {
+            return Err(anyhow!("Telemetry needs temperature or humidity"));
+        }
+        Ok(Self { enabled: Some(fields.to_vec()), template: None })
+    }
+
+    /// Renders payloads through `template` instead; the field list no longer applies.
+    pub fn with_template(mut self, template: Option<Template>) -> Self {
+        self.template = template;
+        self
+    }
+
+    pub fn to_json(&self, telemetry: &Telemetry) -> Result<String> {
+        if let Some(template) = &self.template {
+            return Ok(template.render(&serde_json::to_value(telemetry)?));
+        }
+        let Some(enabled) = &self.enabled else {
+            return Ok(serde_json::to_string(telemetry)?);
+        };
+        let mut value = serde_json::to_value(telemetry)?;
+        if let Value::Object(map) = &mut value {
+            map.retain(|k, _| enabled.contains(&k.as_str()));
+        }
+        Ok(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading() -> Telemetry<'static> {
+        Telemetry {
+            ping: false,
+            device_id: Some("mk2-1"),
+            temperature: Some(21.5),
+            humidity: Some(40.0),
+            sensor: None,
+            ts: Some(1_700_000_000),
+            uptime_ms: Some(5000),
+            time_source: "ntp",
+            read_duration_us: Some(23_000),
+            quality: 1,
+            dropped: 2,
+            battery_pct: Some(80),
+            samples: Some(3),
+            sensor_error: false,
+            auth_mode: Some("wpa2"),
+            unit: "C",
+            boot_count: 7,
+            reset_reason: "power_on",
+            dht_timeouts: 1,
+            dht_checksum_errors: 1,
+            implausible_readings: 1,
+            analog_mv: Some(1200),
+            analog: Some(0.5),
+            read_ms: Some(30),
+            post_ms: Some(120),
+            success_pct: Some(100),
+            wifi_reconnects: Some(0),
+        }
+    }
+
+    #[test]
+    fn filter_keeps_only_enabled_fields() {
+        let filter = FieldFilter::new(&["temperature"]).unwrap();
+        assert_eq!(filter.to_json(&reading()).unwrap(), r#"{"temperature":21.5}"#);
+
+        let filter = FieldFilter::new(&["temperature", "humidity", "ts", "device_id"]).unwrap();
+        let json: Value = serde_json::from_str(&filter.to_json(&reading()).unwrap()).unwrap();
+        let expected = serde_json::json!({
+            "device_id": "mk2-1",
+            "temperature": 21.5,
+            "humidity": 40.0,
+            "ts": 1_700_000_000,
+        });
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn filter_leaves_out_absent_fields() {
+        let telemetry = Telemetry { temperature: None, humidity: None, ..reading() };
+        let filter = FieldFilter::new(&["temperature", "humidity"]).unwrap();
+        assert_eq!(filter.to_json(&telemetry).unwrap(), "{}");
+    }
+
+    fn render(text: &str, telemetry: &Telemetry) -> String {
+        let template = Template::parse(text).unwrap();
+        FieldFilter::new(&[]).unwrap().with_template(Some(template)).to_json(telemetry).unwrap()
+    }
+
+    #[test]
+    fn template_substitutes_fields() {
+        let text = r#"{"t":{{temperature}},"id":{{ device_id }},"src":{{time_source}}}"#;
+        assert_eq!(render(text, &reading()), r#"{"t":21.5,"id":"mk2-1","src":"ntp"}"#);
+        assert_eq!(render("no placeholders", &reading()), "no placeholders");
+    }
+
+    #[test]
+    fn template_fills_absent_fields() {
+        let telemetry = Telemetry { humidity: None, device_id: None, ..reading() };
+        assert_eq!(render("{{humidity}},{{device_id}}", &telemetry), "null,null");
+        let zeroed = Telemetry { quality: 0, dropped: 0, sensor_error: false, ..reading() };
+        let text = "{{quality}},{{dropped}},{{sensor_error}},{{dht_timeouts}}";
+        assert_eq!(render(text, &zeroed), "0,0,false,1");
+    }
+
+    #[test]
+    fn template_rejects_bad_placeholders() {
+        let err = Template::parse(r#"{"t":{{temperature}"#).err().unwrap();
+        assert_eq!(err, "unclosed '{{' in template");
+        let err = Template::parse("{{colour}}").err().unwrap();
+        assert_eq!(err, "unknown template field 'colour'");
+    }
+
+    #[test]
+    fn filter_needs_a_reading_field() {
+        assert!(FieldFilter::new(&["ts", "device_id"]).is_err());
+        assert!(FieldFilter::new(&["temperature", "colour"]).is_err());
+    }
+}
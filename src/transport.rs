@@ -0,0 +1,731 @@
+use crate::backoff::{self, Backoff};
+use crate::clock::Clock;
+use crate::error::AppError;
+use crate::outbox::{Outbox, Payload, Settled};
+use crate::signing;
+use crate::telemetry::Encoding;
+use embedded_svc::http::client::Client;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::client::{Configuration as HttpCfg, EspHttpConnection};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload, MqttClientConfiguration, QoS};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Most of a response body kept for the log; the rest is discarded
+const MAX_RESPONSE_BODY: usize = 2048;
+
+/// Client settings for `url`. An https URL verifies the server against the IDF
+/// certificate bundle, which needs the `tls` feature. Every connect, write and
+/// read gives up after `HTTP_TIMEOUT`.
+pub fn http_config(url: &str) -> HttpCfg {
+    let https = url.starts_with("https://");
+    let timeout = Some(crate::HTTP_TIMEOUT);
+    #[cfg(not(feature = "tls"))]
+    if https {
+        log::warn!("{url} needs TLS, but this build has no `tls` feature");
+    }
+    #[cfg(feature = "tls")]
+    if https {
+        return HttpCfg {
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            timeout,
+            ..Default::default()
+        };
+    }
+    HttpCfg { timeout, ..Default::default() }
+}
+
+pub fn is_mqtt(url: &str) -> bool {
+    url.starts_with("mqtt://") || url.starts_with("mqtts://")
+}
+
+/// Broker settings for `url`, verifying mqtts:// like `http_config` does https.
+fn mqtt_config(url: &str) -> MqttClientConfiguration<'static> {
+    let tls = url.starts_with("mqtts://");
+    #[cfg(not(feature = "tls"))]
+    if tls {
+        log::warn!("{url} needs TLS, but this build has no `tls` feature");
+    }
+    #[cfg(feature = "tls")]
+    if tls {
+        return MqttClientConfiguration {
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        };
+    }
+    MqttClientConfiguration::default()
+}
+
+/// Somewhere a payload can be delivered. `send` returns the 2xx status that
+/// acked it; any other answer is `AppError::Http`.
+pub trait Transport {
+    fn name(&self) -> &str;
+
+    fn send(&mut self, body: &[u8]) -> Result<u16, AppError>;
+
+    /// Sends a queued payload, joined into one body unless the transport can
+    /// write it piece by piece.
+    fn send_payload(&mut self, payload: &Payload) -> Result<u16, AppError> {
+        self.send(payload.joined().as_bytes())
+    }
+
+    /// Points the transport at a new endpoint, for those that have one.
+    fn set_endpoint(&mut self, _url: &str) {}
+
+    /// `Date` header of the last response, if the transport saw one.
+    fn take_server_date(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Firmware URL offered by the last response, if the transport saw one.
+    fn take_firmware_url(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Writes on the device itself: its deliveries say nothing about the
+    /// network or the server.
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    /// True once the transport has stopped for good, e.g. its card is gone;
+    /// the sink is then dropped along with its queue.
+    fn gave_up(&self) -> bool {
+        false
+    }
+}
+
+/// Signs each request with a shared key and the current time.
+pub struct Signer {
+    pub key: &'static [u8],
+    pub clock: Arc<Mutex<Clock>>,
+}
+
+pub struct HttpTransport {
+    // The endpoint that last answered 2xx, tried first
+    url: String,
+    // Tried in order after `url`; empty without fallbacks
+    endpoints: Vec<String>,
+    server_date: Option<String>,
+    // From an `X-Mk2-Firmware` response header
+    firmware_url: Option<String>,
+    signer: Option<Signer>,
+    // Full `Authorization` header value, kept out of every log line
+    authorization: Option<String>,
+    // Cleared when the server answers a gzipped body with 415
+    #[cfg_attr(not(feature = "gzip"), allow(dead_code))]
+    gzip: bool,
+    // Back to JSON when the server answers a CBOR body with 415
+    encoding: Encoding,
+    // Kept between sends when `reuse` is set; dropped on any failure
+    client: Option<Client<EspHttpConnection>>,
+    reuse: bool,
+    method: Method,
+    // Configured extras, sent after the built-in headers
+    extra_headers: Vec<(String, String)>,
+    // Write batches reading by reading instead of joining them first
+    stream: bool,
+}
+
+/// What one POST got back, read off before the connection is reused.
+struct Exchange {
+    status: u16,
+    date: Option<String>,
+    firmware_url: Option<String>,
+    detail: Vec<u8>,
+}
+
+fn exchange(
+    client: &mut Client<EspHttpConnection>,
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[&[u8]],
+) -> Result<Exchange, AppError> {
+    let mut req = client.request(method, url, headers).map_err(AppError::send)?;
+
+    for chunk in body {
+        req.write_all(chunk).map_err(AppError::send)?;
+    }
+
+    let mut resp = req.submit().map_err(AppError::send)?;
+    let date = resp.header("Date").map(str::to_owned);
+    let firmware_url = resp.header("X-Mk2-Firmware").map(str::to_owned);
+    let status = resp.status();
+
+    let mut detail = Vec::new();
+    let mut buf = [0u8; 256];
+    while detail.len() < MAX_RESPONSE_BODY {
+        match resp.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => detail.extend_from_slice(&buf[..n]),
+            // The status already arrived; a cut-off body only loses the detail
+            Err(e) => {
+                log::debug!("Response body unreadable: {:?}", e);
+                break;
+            }
+        }
+    }
+    detail.truncate(MAX_RESPONSE_BODY);
+    Ok(Exchange { status, date, firmware_url, detail })
+}
+
+impl HttpTransport {
+    pub fn new(url: &str, signer: Option<Signer>, token: Option<&str>) -> Self {
+        Self {
+            url: url.to_owned(),
+            endpoints: Vec::new(),
+            server_date: None,
+            firmware_url: None,
+            signer,
+            authorization: token.map(|token| format!("Bearer {token}")),
+            gzip: cfg!(feature = "gzip"),
+            encoding: Encoding::Json,
+            client: None,
+            reuse: false,
+            method: Method::Post,
+            extra_headers: Vec::new(),
+            stream: false,
+        }
+    }
+
+    /// Sends with `method` instead of POST, adding `headers` to each request.
+    pub fn request_with(mut self, method: Method, headers: Vec<(String, String)>) -> Self {
+        self.method = method;
+        self.extra_headers = headers;
+        self
+    }
+
+    /// Keeps one connection open across sends instead of opening one per
+    /// post. A failed send drops it and the next one reconnects.
+    pub fn reusing(mut self, reuse: bool) -> Self {
+        self.reuse = reuse;
+        self
+    }
+
+    /// Tries `fallbacks` in order when the endpoint gives no 2xx. The one that
+    /// answers is tried first from then on, so a failover is paid once.
+    pub fn falling_back(mut self, fallbacks: Vec<String>) -> Self {
+        if !fallbacks.is_empty() {
+            self.endpoints = std::iter::once(self.url.clone()).chain(fallbacks).collect();
+        }
+        self
+    }
+
+    /// Encodes every body as `encoding`; queued payloads stay JSON, so a
+    /// change only applies to what is sent.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Writes batched payloads one reading at a time, so a flush never holds
+    /// a second, joined copy of the batch. Gzipped bodies are still joined.
+    pub fn streaming(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Sends `body`, given in pieces, with its length up front: chunked
+    /// encoding is left out, since not every server takes it and PUT or
+    /// PATCH would otherwise go out claiming an empty body.
+    fn send_chunks(&mut self, body: &[&[u8]], gzipped: bool) -> Result<u16, AppError> {
+        // Signed as sent, so the server checks the bytes it received
+        let mut signature = None;
+        if let Some(signer) = &self.signer {
+            // An unverifiable signature is worse than a late one: keep it queued
+            let Some(timestamp) = signer.clock.lock().unwrap().synced_unix_secs() else {
+                return Err(AppError::Held("clock not synced, waiting to sign".into()));
+            };
+            signature = Some((timestamp.to_string(), signing::sign(signer.key, timestamp, body)));
+        }
+        let length = body.iter().map(|chunk| chunk.len()).sum::<usize>().to_string();
+        let content_type = self.encoding.content_type();
+        let mut headers = vec![("Content-Type", content_type), ("Content-Length", length.as_str())];
+        if gzipped {
+            headers.push(("Content-Encoding", "gzip"));
+        } else if self.reuse {
+            // The IDF client keeps headers between requests, so undo a gzip one
+            headers.push(("Content-Encoding", "identity"));
+        }
+        if let Some((timestamp, sig)) = &signature {
+            headers.push(("X-Mk2-Timestamp", timestamp.as_str()));
+            headers.push(("X-Mk2-Signature", sig.as_str()));
+        }
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization.as_str()));
+        }
+        for (name, value) in &self.extra_headers {
+            headers.push((name.as_str(), value.as_str()));
+        }
+
+        // The last endpoint to answer 2xx first, then the rest in their order
+        let mut order = vec![self.url.clone()];
+        order.extend(self.endpoints.iter().filter(|url| **url != self.url).cloned());
+        let mut failure = None;
+        for url in order {
+            let started = Instant::now();
+            // The server may have closed an idle kept connection; then it is
+            // dropped and the post goes out once more on a new one. A kept
+            // connection only ever belongs to the current endpoint.
+            let kept = self.client.take().filter(|_| url == self.url).and_then(|mut client| {
+                match exchange(&mut client, self.method, &url, &headers, body) {
+                    Ok(reply) => Some((client, reply)),
+                    Err(e) => {
+                        log::debug!("Kept connection failed ({e}), reconnecting");
+                        None
+                    }
+                }
+            });
+            let reused = kept.is_some();
+            let (client, reply) = match kept {
+                Some(kept) => kept,
+                None => {
+                    let fresh = EspHttpConnection::new(&http_config(&url))
+                        .map_err(AppError::send)
+                        .and_then(|conn| {
+                            let mut client = Client::wrap(conn);
+                            let reply =
+                                exchange(&mut client, self.method, &url, &headers, body)?;
+                            Ok((client, reply))
+                        });
+                    match fresh {
+                        Ok(fresh) => fresh,
+                        Err(e) => {
+                            let took = started.elapsed().as_millis();
+                            log::warn!("Request to {url} failed after {took} ms: {e}");
+                            failure = Some(e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            if self.reuse {
+                self.client = Some(client);
+            }
+            let took = started.elapsed().as_millis();
+
+            if let Some(date) = reply.date {
+                self.server_date = Some(date);
+            }
+            if let Some(firmware) = reply.firmware_url {
+                self.firmware_url = Some(firmware);
+            }
+            let status = reply.status;
+            let how = if reused { " (reused connection)" } else { "" };
+            let via =
+                if self.endpoints.is_empty() { String::new() } else { format!(" from {url}") };
+            log::info!(target: crate::CYCLE_LOG, "📨 Status: {status}{via} in {took} ms{how}");
+
+            let detail = String::from_utf8_lossy(&reply.detail);
+            log::debug!("Response body ({} bytes): {detail}", detail.len());
+            if gzipped && status == 415 {
+                // The outbox retries the payload, this time uncompressed
+                log::warn!("{url} refuses gzip bodies (415), sending them uncompressed");
+                self.gzip = false;
+                return Err(AppError::Http(status));
+            }
+            if self.encoding == Encoding::Cbor && status == 415 {
+                log::warn!("{url} refuses CBOR bodies (415), sending JSON instead");
+                self.encoding = Encoding::Json;
+                return Err(AppError::Http(status));
+            }
+            if status == 401 {
+                let sent = if self.authorization.is_some() { "the token" } else { "no token" };
+                log::error!("Auth rejected by {url} (401), {sent} was sent: {detail}");
+            } else if (400..500).contains(&status) {
+                // The payload itself is wrong, so show why without debug logs; a
+                // 5xx is just retried by the outbox
+                log::error!("Server rejected the payload with {status}: {detail}");
+            }
+            if !(200..300).contains(&status) {
+                failure = Some(AppError::Http(status));
+                // Another endpoint gets a fresh connection
+                self.client = None;
+                continue;
+            }
+            if url != self.url {
+                log::info!("📨 {url} answered, sending there first from now on");
+                self.url = url;
+            }
+            return Ok(status);
+        }
+        // The order always holds at least the current endpoint
+        Err(failure.unwrap_or_else(|| AppError::Send("no endpoint".into())))
+    }
+}
+
+impl Transport for HttpTransport {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn send(&mut self, body: &[u8]) -> Result<u16, AppError> {
+        #[cfg(feature = "cbor")]
+        let encoded = match self.encoding {
+            Encoding::Cbor => Some(crate::cbor::from_json(body)?),
+            Encoding::Json => None,
+        };
+        #[cfg(not(feature = "cbor"))]
+        let encoded: Option<Vec<u8>> = None;
+        let body = encoded.as_deref().unwrap_or(body);
+        #[cfg(feature = "gzip")]
+        let compressed = (self.gzip && body.len() > crate::GZIP_MIN_BYTES)
+            .then(|| crate::gzip::compress(body));
+        #[cfg(not(feature = "gzip"))]
+        let compressed: Option<Vec<u8>> = None;
+        let gzipped = compressed.is_some();
+        let body = compressed.as_deref().unwrap_or(body);
+        self.send_chunks(&[body], gzipped)
+    }
+
+    fn send_payload(&mut self, payload: &Payload) -> Result<u16, AppError> {
+        // Compression and CBOR need the whole body anyway
+        #[cfg(feature = "gzip")]
+        let gzip = self.gzip && payload.size() > crate::GZIP_MIN_BYTES;
+        #[cfg(not(feature = "gzip"))]
+        let gzip = false;
+        if !self.stream || gzip || self.encoding == Encoding::Cbor {
+            return self.send(payload.joined().as_bytes());
+        }
+        let chunks: Vec<&[u8]> = payload.chunks().map(str::as_bytes).collect();
+        self.send_chunks(&chunks, false)
+    }
+
+    fn set_endpoint(&mut self, url: &str) {
+        if url != self.url {
+            // http and https need differently configured connections
+            self.client = None;
+        }
+        self.url = url.to_owned();
+        // A new primary; the fallbacks stay behind it
+        if let Some(primary) = self.endpoints.first_mut() {
+            *primary = url.to_owned();
+        }
+    }
+
+    fn take_server_date(&mut self) -> Option<String> {
+        self.server_date.take()
+    }
+
+    fn take_firmware_url(&mut self) -> Option<String> {
+        self.firmware_url.take()
+    }
+}
+
+/// Publishes each payload to `topic` on an MQTT broker. The client reconnects
+/// by itself after a drop; until it is back, sends fail and the sink backs off.
+pub struct MqttTransport {
+    url: String,
+    topic: String,
+    client: Option<EspMqttClient<'static>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl MqttTransport {
+    pub fn new(url: &str, topic: String) -> Self {
+        let connected = Arc::new(AtomicBool::new(false));
+        Self { url: url.to_owned(), topic, client: None, connected }
+    }
+
+    fn connect(&self) -> Result<EspMqttClient<'static>, AppError> {
+        let connected = self.connected.clone();
+        let client = EspMqttClient::new_cb(&self.url, &mqtt_config(&self.url), move |event| {
+            match event.payload() {
+                EventPayload::Connected(_) => connected.store(true, Ordering::Relaxed),
+                EventPayload::Disconnected => connected.store(false, Ordering::Relaxed),
+                _ => {}
+            }
+        })
+        .map_err(AppError::send)?;
+        log::info!("📡 MQTT client started for {}", self.url);
+        Ok(client)
+    }
+}
+
+impl Transport for MqttTransport {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn send(&mut self, body: &[u8]) -> Result<u16, AppError> {
+        // Created on first use, and again on a later cycle if that failed
+        if self.client.is_none() {
+            self.client = Some(self.connect()?);
+        }
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(AppError::Send(format!("MQTT broker {} not connected", self.url)));
+        }
+        let client = self.client.as_mut().unwrap();
+        client.publish(&self.topic, QoS::AtLeastOnce, false, body).map_err(AppError::send)?;
+        log::info!(target: crate::CYCLE_LOG, "📨 Published to {}", self.topic);
+        Ok(200)
+    }
+
+    fn set_endpoint(&mut self, url: &str) {
+        if url != self.url {
+            self.url = url.to_owned();
+            // Dropping the client disconnects; the next send connects to the new broker.
+            // A fresh flag keeps the old client's last events from touching it.
+            self.client = None;
+            self.connected = Arc::new(AtomicBool::new(false));
+        }
+    }
+}
+
+/// A transport with its own queue: each sink retries and acks independently,
+/// so one being down never holds back or drops data for the others. After a
+/// failed flush it waits out its backoff before trying again.
+pub struct Sink {
+    transport: Box<dyn Transport>,
+    outbox: Outbox,
+    delivered: u32,
+    failed_flushes: u32,
+    // Failed flushes since the last delivery
+    failure_streak: u32,
+    // Status of the last send that got an answer
+    last_status: Option<u16>,
+    // The last flush stopped at a payload held back, not at a failure
+    holding: bool,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+impl Sink {
+    pub fn new(transport: Box<dyn Transport>, outbox: Outbox, backoff: Backoff) -> Self {
+        Self {
+            transport,
+            outbox,
+            delivered: 0,
+            failed_flushes: 0,
+            failure_streak: 0,
+            last_status: None,
+            holding: false,
+            backoff,
+            retry_at: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.transport.name()
+    }
+
+    pub fn push(&mut self, payload: impl Into<Payload>) {
+        self.outbox.push(payload.into(), 0..0);
+    }
+
+    /// Queues a payload carrying the history samples `samples`, so their
+    /// outcome can be told through `take_settled`.
+    pub fn push_samples(&mut self, payload: impl Into<Payload>, samples: Range<u32>) {
+        self.outbox.push(payload.into(), samples);
+    }
+
+    pub fn take_settled(&mut self) -> Settled {
+        self.outbox.take_settled()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outbox.is_empty()
+    }
+
+    pub fn payloads(&self) -> impl DoubleEndedIterator<Item = &Payload> {
+        self.outbox.payloads()
+    }
+
+    pub fn set_endpoint(&mut self, url: &str) {
+        self.transport.set_endpoint(url);
+    }
+
+    pub fn take_server_date(&mut self) -> Option<String> {
+        self.transport.take_server_date()
+    }
+
+    pub fn take_firmware_url(&mut self) -> Option<String> {
+        self.transport.take_firmware_url()
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.transport.is_local()
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.transport.gave_up()
+    }
+
+    pub fn failure_streak(&self) -> u32 {
+        self.failure_streak
+    }
+
+    pub fn last_status(&self) -> Option<u16> {
+        self.last_status
+    }
+
+    /// Whether the last flush kept its payloads back on purpose; that is
+    /// neither a failure nor a reason to back off.
+    pub fn is_holding(&self) -> bool {
+        self.holding
+    }
+
+    /// When the next flush may send, if anything is queued.
+    pub fn next_attempt(&self) -> Option<Instant> {
+        if self.outbox.is_empty() {
+            return None;
+        }
+        Some(self.retry_at.unwrap_or_else(Instant::now))
+    }
+
+    /// Flushes the queue unless backing off; returns how many payloads were acked.
+    pub fn flush(&mut self) -> usize {
+        if self.outbox.is_empty() || self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return 0;
+        }
+        let transport = &mut self.transport;
+        let last_status = &mut self.last_status;
+        let mut held = None;
+        let delivered = self.outbox.flush(|payload| {
+            let sent = transport.send_payload(payload);
+            if let Ok(status) | Err(AppError::Http(status)) = sent {
+                *last_status = Some(status);
+            }
+            held = match &sent {
+                Err(AppError::Held(why)) => Some(why.clone()),
+                _ => None,
+            };
+            sent
+        });
+        if let Some(why) = held.as_ref().filter(|_| !self.holding) {
+            log::warn!("{} sink: {why}, holding its queue", self.transport.name());
+        }
+        self.holding = held.is_some();
+        self.delivered += delivered as u32;
+        if delivered > 0 {
+            self.failure_streak = 0;
+        }
+        if self.outbox.is_empty() {
+            self.backoff.reset();
+            self.retry_at = None;
+        } else if self.holding {
+            // Checked again after the base delay, so the loop doesn't spin
+            self.retry_at = Some(Instant::now() + self.backoff.base());
+        } else {
+            self.failed_flushes += 1;
+            if delivered == 0 {
+                self.failure_streak += 1;
+            }
+            // Something got through, so the endpoint is up: start over from the base delay
+            if delivered > 0 {
+                self.backoff.reset();
+            }
+            let delay = self.backoff.next_delay(backoff::random());
+            self.retry_at = Some(Instant::now() + delay);
+            log::warn!(
+                "{} sink: backlog pending ({} delivered, {} failed flushes), retrying in {:?}",
+                self.transport.name(),
+                self.delivered,
+                self.failed_flushes,
+                delay
+            );
+        }
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Answers sends from a script, then with 200 once it runs out, and
+    /// records every body it was handed.
+    struct Scripted {
+        script: VecDeque<Result<u16, AppError>>,
+        sent: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Transport for Scripted {
+        fn name(&self) -> &str {
+            "scripted"
+        }
+
+        fn send(&mut self, body: &[u8]) -> Result<u16, AppError> {
+            self.sent.borrow_mut().push(String::from_utf8_lossy(body).into_owned());
+            self.script.pop_front().unwrap_or(Ok(200))
+        }
+    }
+
+    fn sink(script: Vec<Result<u16, AppError>>) -> (Sink, Rc<RefCell<Vec<String>>>) {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let transport = Scripted { script: script.into(), sent: sent.clone() };
+        let backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+        (Sink::new(Box::new(transport), Outbox::new(10, 3), backoff), sent)
+    }
+
+    #[test]
+    fn drains_in_order_after_failures() {
+        let (mut sink, sent) = sink(vec![
+            Err(AppError::Send("timeout".into())),
+            Err(AppError::Http(503)),
+            Err(AppError::Send("refused".into())),
+        ]);
+        for body in ["a", "b", "c"] {
+            sink.push(body.to_owned());
+        }
+        for streak in 1..=3 {
+            assert_eq!(sink.flush(), 0);
+            assert_eq!(sink.failure_streak(), streak);
+            assert!(sink.next_attempt().is_some_and(|at| at > Instant::now()));
+            // Backing off: nothing is sent until the delay is over
+            assert_eq!(sink.flush(), 0);
+            sink.retry_at = None;
+        }
+        assert_eq!(sink.last_status(), Some(503));
+        assert_eq!(sink.flush(), 3);
+        assert_eq!(sink.failure_streak(), 0);
+        assert!(sink.is_empty());
+        assert_eq!(sink.next_attempt(), None);
+        assert_eq!(sink.last_status(), Some(200));
+        assert_eq!(*sent.borrow(), ["a", "a", "a", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn partial_flush_keeps_the_rest_queued_in_order() {
+        let (mut sink, sent) = sink(vec![Ok(200), Err(AppError::Send("reset".into()))]);
+        for body in ["a", "b", "c"] {
+            sink.push(body.to_owned());
+        }
+        assert_eq!(sink.flush(), 1);
+        // Something got through, so the streak doesn't grow
+        assert_eq!(sink.failure_streak(), 0);
+        let queued: Vec<_> = sink.payloads().map(|p| p.joined().into_owned()).collect();
+        assert_eq!(queued, ["b", "c"]);
+        sink.retry_at = None;
+        assert_eq!(sink.flush(), 2);
+        assert_eq!(*sent.borrow(), ["a", "b", "b", "c"]);
+    }
+
+    #[test]
+    fn held_payloads_are_no_failure() {
+        let held = || Err(AppError::Held("clock not synced".into()));
+        let (mut sink, sent) = sink(vec![held(), held()]);
+        sink.push("a".to_owned());
+        for _ in 0..2 {
+            let before = Instant::now();
+            assert_eq!(sink.flush(), 0);
+            assert!(sink.is_holding());
+            assert_eq!(sink.failure_streak(), 0);
+            // Always the base delay, never a growing one
+            let wait = sink.next_attempt().unwrap().duration_since(before);
+            assert!(wait >= Duration::from_secs(5) && wait < Duration::from_secs(6));
+            sink.retry_at = None;
+        }
+        assert_eq!(sink.flush(), 1);
+        assert!(!sink.is_holding());
+        assert_eq!(sent.borrow().len(), 3);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+/// Decodes a form-urlencoded value: `+` is a space and `%XX` a byte. A `%`
+/// not followed by two hex digits, including one cut short at the end of the
+/// input, is kept as is. Bytes that are not UTF-8 become U+FFFD.
+pub fn url_decode(bytes: &[u8]) -> String {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            // Both digits must be in the input, so a final "%XX" still decodes
+            b'%' if i + 2 < bytes.len() => {
+                let hv = |c| match c {
+                    b'0'..=b'9' => Some(c - b'0'),
+                    b'a'..=b'f' => Some(c - b'a' + 10),
+                    b'A'..=b'F' => Some(c - b'A' + 10),
+                    _ => None,
+                };
+                if let (Some(h), Some(l)) = (hv(bytes[i + 1]), hv(bytes[i + 2])) {
+                    out.push((h << 4) | l);
+                    i += 2;
+                } else {
+                    out.push(bytes[i]);
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses an `application/x-www-form-urlencoded` body. Keys and values are
+/// decoded, a pair without `=` has an empty value, and a repeated key keeps
+/// its last value.
+pub fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(key.as_bytes()), url_decode(value.as_bytes()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(s: &str) -> String {
+        url_decode(s.as_bytes())
+    }
+
+    #[test]
+    fn url_decode_escapes() {
+        assert_eq!(decode("%20"), " ");
+        assert_eq!(decode("a+b"), "a b");
+        assert_eq!(decode("a%2Bb"), "a+b");
+        assert_eq!(decode("%C3%A9"), "é");
+    }
+
+    #[test]
+    fn url_decode_keeps_bad_escapes() {
+        assert_eq!(decode("%zz"), "%zz");
+        assert_eq!(decode("%"), "%");
+        assert_eq!(decode("%2"), "%2");
+        assert_eq!(decode("ab%"), "ab%");
+        assert_eq!(decode("ab%4"), "ab%4");
+        assert_eq!(decode("%g1%41"), "%g1A");
+    }
+
+    #[test]
+    fn url_decode_invalid_utf8() {
+        assert_eq!(decode("%FFa"), "\u{FFFD}a");
+    }
+
+    #[test]
+    fn parse_form_pairs() {
+        assert!(parse_form("").is_empty());
+        let form = parse_form("ssid=home&flag&pass=");
+        assert_eq!(form.len(), 3);
+        assert_eq!(form["ssid"], "home");
+        assert_eq!(form["flag"], "");
+        assert_eq!(form["pass"], "");
+    }
+
+    #[test]
+    fn parse_form_repeated_key_keeps_last() {
+        let form = parse_form("a=1&a=2");
+        assert_eq!(form.len(), 1);
+        assert_eq!(form["a"], "2");
+    }
+
+    #[test]
+    fn parse_form_decodes_keys_and_values() {
+        let form = parse_form("my+key=a%3Db%26c&%73sid=caf%C3%A9");
+        assert_eq!(form["my key"], "a=b&c");
+        assert_eq!(form["ssid"], "café");
+    }
+
+    #[test]
+    fn parse_form_skips_empty_pairs() {
+        let form = parse_form("a=1&&b=2&");
+        assert_eq!(form.len(), 2);
+        assert_eq!(form["a"], "1");
+        assert_eq!(form["b"], "2");
+    }
+}